@@ -1,11 +1,21 @@
 pub mod compact;
 
+use std::borrow::Cow;
 use std::cmp::Ordering;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::ops::Range;
+use std::path::Path;
+use std::path::PathBuf;
 use std::str::Utf8Error;
 
 use compact_str::CompactString as String;
 use foldhash::HashMap;
 use foldhash::HashSet;
+use quick_error::ResultExt;
+use rayon::prelude::*;
 use sha3::Digest;
 use sha3::Sha3_224;
 
@@ -16,40 +26,1147 @@ quick_error! {
         BadMagic
         BadAddress
         BadFunctionName
+        BadFunctionTable(err: u32)
         EpilogueNotEmpty
-        DecodeUtf16
+        LoneSurrogate(name: String, addr: u32)
         DecodeUtf8(err: Utf8Error) { from() }
         UnknownSection(err: [u8; 8])
         BadSection(err: [u8; 8])
+        EmptyFunctionSection
+        NoNamedFunctions
         IncompatibleGlobal
         IncompatibleData
+        IncompatibleConststr
+        IncompatibleLinkinf
         HashMismatch
         NoMods
         ModsConflicts(err: String)
+        UnknownFunction(err: String)
+        FunctionExists(err: String)
+        NameRoundTrip(err: String)
+        UnsupportedVersion(err: u8)
+        UnknownHash(err: u8)
+        CorruptEntry(err: String)
+        DuplicateEntry(err: String)
+        DuplicateFunction(err: String)
+        FunctionTooLarge(name: String, size: usize)
+        LimitExceeded(err: &'static str)
+        FileIO(path: PathBuf, err: std::io::Error) {
+            context(path: &'a Path, err: std::io::Error) -> (path.to_path_buf(), err)
+        }
         IO(err: std::io::Error) { from() }
     }
 }
 
-type Hash = [u8; 224 / 8];
-const MAGIC: &[u8; 56] = b"Entis\x1a\x00\x00\xff\xff\xff\xff\x00\x00\x00\x00Cotopha Image file\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00";
-const PROLOGUE: &[u8; 22] = b"@\0I\0n\0i\0t\0i\0a\0l\0i\0z\0e\0";
-// const EMPTY_PROLOGUE: &[u8; 33] =
-//     b"\x04\x0b\x00\x00\x00@\0I\0n\0i\0t\0i\0a\0l\0i\0z\0e\0\x00\x00\x00\x00\x09\x01";
+/// How parsing copes with what it finds, and where it reports its steps.
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Fail on function names that aren't well-formed UTF-16, rather than
+    /// decode them lossily with a warning.
+    pub strict_utf16: bool,
+    /// Called with every step the parser takes, to debug files it rejects.
+    pub trace: Option<fn(&str)>,
+    /// Algorithms to digest a base by besides its own, for archives that
+    /// identify it by another one.
+    pub also_digest: Vec<HashAlgo>,
+}
+
+/// Something off about a file that parsing let through, left to the caller
+/// to report.
+#[derive(Debug)]
+pub enum Warning {
+    /// A function name with unpaired surrogates, at this image offset, was
+    /// decoded lossily.
+    LoneSurrogate(String, u32),
+    /// The header declares this many bytes of sections, but this many follow
+    /// it, so the length was ignored.
+    HeaderLength(u64, u64),
+    /// The base has no named functions to diff against, so every entry of
+    /// the archive is stored standalone.
+    NoBaseFunctions,
+    /// `global` and `data` are this many bytes, one tiny and the other
+    /// large, so the two may have been swapped.
+    SwappedSections(usize, usize),
+}
+
+/// What a parse carries along: its options and the warnings so far.
+struct Parse<'o> {
+    options: &'o ParseOptions,
+    warnings: Vec<Warning>,
+}
+
+impl<'o> Parse<'o> {
+    fn new(options: &'o ParseOptions) -> Self {
+        Self {
+            options,
+            warnings: vec![],
+        }
+    }
+}
+
+macro_rules! trace {
+    ($parse:expr, $($arg:tt)*) => {
+        if let Some(trace) = $parse.options.trace {
+            trace(&format!($($arg)*));
+        }
+    };
+}
+
+pub type Hash = [u8; 224 / 8];
+/// Number of functions from which `CSX::new` builds them in parallel.
+const PARALLEL_FUNCTIONS: usize = 1024;
+const MAGIC: &[u8; 56] = b"Entis\x1a\x00\x00\xff\xff\xff\xff\x00\x00\x00\x00Cotopha Image file\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+/// Section `rebuild` records the tag in, which the engine doesn't know.
+const TAG: &[u8; 8] = b"nyandere";
+/// How small one of `global` and `data` has to be, and how big the other, for
+/// a base to look like it has them swapped. Neither may be empty, so a near
+/// empty one is what gives a mixup away.
+const SWAP_HINT: (usize, usize) = (8, 0x10000);
+const PROLOGUE: &[u8; 22] = b"@\0I\0n\0i\0t\0i\0a\0l\0i\0z\0e\0";
+const EMPTY_PROLOGUE: &[u8; 33] =
+    b"\x04\x0b\x00\x00\x00@\0I\0n\0i\0t\0i\0a\0l\0i\0z\0e\0\x00\x00\x00\x00\x09\x01";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HashAlgo {
+    #[default]
+    Sha3_224,
+    /// BLAKE3 truncated to the same 224 bits as the default.
+    Blake3,
+}
+
+impl HashAlgo {
+    pub fn digest(self, data: &[u8]) -> Hash {
+        match self {
+            HashAlgo::Sha3_224 => sha3_224(data),
+            HashAlgo::Blake3 => {
+                let hash = blake3::hash(data);
+                *hash.as_bytes().first_chunk().unwrap()
+            }
+        }
+    }
+
+    pub fn id(self) -> u8 {
+        match self {
+            HashAlgo::Sha3_224 => 0,
+            HashAlgo::Blake3 => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self, Error> {
+        match id {
+            0 => Ok(HashAlgo::Sha3_224),
+            1 => Ok(HashAlgo::Blake3),
+            _ => Err(Error::UnknownHash(id)),
+        }
+    }
+}
+
+impl std::str::FromStr for HashAlgo {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha3-224" => Ok(HashAlgo::Sha3_224),
+            "blake3" => Ok(HashAlgo::Blake3),
+            _ => Err("expected `sha3-224` or `blake3`"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TableOrder {
+    /// Named functions sorted by their UTF-16 names.
+    #[default]
+    Sorted,
+    /// Named functions in the order the base table listed them, followed by
+    /// functions added by mods.
+    Base,
+}
+
+/// What `rebuild` does with `@Initialize` prologues when there is more than
+/// one, e.g. after mods added their own. The engine is assumed to run every
+/// prologue listed in the function table, in image order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProloguePolicy {
+    /// Every prologue, as applied.
+    #[default]
+    KeepAll,
+    /// Only the first prologue, usually the base one.
+    KeepFirst,
+    /// Only the last prologue, usually from the last mods.
+    KeepLast,
+    /// A single prologue running each one's code in turn: the name header
+    /// of every prologue but the first is dropped. This assumes prologue
+    /// code falls through to whatever follows it and does not depend on
+    /// where it starts, which holds only for some engines.
+    Merge,
+}
+
+impl std::str::FromStr for ProloguePolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep-all" => Ok(ProloguePolicy::KeepAll),
+            "keep-first" => Ok(ProloguePolicy::KeepFirst),
+            "keep-last" => Ok(ProloguePolicy::KeepLast),
+            "merge" => Ok(ProloguePolicy::Merge),
+            _ => Err("expected `keep-all`, `keep-first`, `keep-last` or `merge`"),
+        }
+    }
+}
+
+pub struct CSX {
+    base_hash: Hash,
+    hash_algo: HashAlgo,
+    /// Digests of a base by `ParseOptions::also_digest`.
+    digests: Vec<(HashAlgo, Hash)>,
+    base_func: HashMap<String, usize>,
+    base_table: Vec<usize>,
+    table_order: TableOrder,
+    prologue_policy: ProloguePolicy,
+    tag: Vec<u8>,
+    mods_used: HashSet<String>,
+    global: Vec<u8>,
+    data: Vec<u8>,
+    /// Empty where the file has the usual stub of 4 zero bytes.
+    conststr: Vec<u8>,
+    /// Empty where the file has the usual stub of 16 zero bytes.
+    linkinf: Vec<u8>,
+    functions: Vec<Function>,
+}
+
+impl CSX {
+    fn new_(
+        csx: &mut &[u8],
+        base: bool,
+        hash_algo: HashAlgo,
+        options: &ParseOptions,
+    ) -> Result<(Self, SectionLayout, Vec<Warning>), Error> {
+        let (base_hash, digests) = if base {
+            let digests = other_digests(csx, hash_algo, options);
+            (hash_algo.digest(csx), digests)
+        } else {
+            <_>::default()
+        };
+        let mut parse = Parse::new(options);
+        let sections = Sections::new(csx, base, &mut parse)?;
+        let mut image = sections.image;
+
+        let (mut addr_splits, named) = sections.addrs(&parse)?;
+        let base_table = named
+            .iter()
+            .map(|addr| addr_splits.binary_search(addr).unwrap())
+            .collect();
+        let starts = addr_splits.clone();
+        for i in 0..addr_splits.len() - 1 {
+            addr_splits[i] = addr_splits[i + 1] - addr_splits[i];
+        }
+        addr_splits.pop();
+
+        let mut slices = Vec::with_capacity(addr_splits.len());
+        for (size, addr) in std::iter::zip(addr_splits, starts) {
+            slices.push((addr, image.split_off(..size as usize).expect_eof()?));
+        }
+        let strict = options.strict_utf16;
+        let function = |&(addr, bytecode): &(u32, &[u8])| {
+            let (name, warning) = from_utf16(extract_name(bytecode, 0)?, addr, strict)?;
+            let bytecode = bytecode.to_vec();
+            Ok::<_, Error>((Function { name, bytecode }, warning))
+        };
+        // Functions are independent once sliced, but spreading them over
+        // threads only pays off for large images.
+        let functions: Vec<_> = if slices.len() < PARALLEL_FUNCTIONS {
+            slices.iter().map(function).collect::<Result<_, _>>()?
+        } else {
+            slices.par_iter().map(function).collect::<Result<_, _>>()?
+        };
+        let (functions, warnings): (Vec<_>, Vec<_>) = functions.into_iter().unzip();
+        parse.warnings.extend(warnings.into_iter().flatten());
+        for f in &functions {
+            let size = f.bytecode.len();
+            trace!(parse, "trace: function `{}`, size {size}", f.name);
+        }
+
+        let base_func = if base {
+            functions
+                .iter()
+                .enumerate()
+                .filter(|(_, f)| !f.name.starts_with("@"))
+                .map(|(i, f)| (f.name.clone(), i))
+                .collect()
+        } else {
+            <_>::default()
+        };
+
+        let csx = Self {
+            base_hash,
+            hash_algo,
+            digests,
+            base_func,
+            base_table,
+            table_order: <_>::default(),
+            prologue_policy: <_>::default(),
+            tag: vec![],
+            mods_used: <_>::default(),
+            global: sections.global.to_vec(),
+            data: sections.data.to_vec(),
+            conststr: sections.conststr.to_vec(),
+            linkinf: sections.linkinf.to_vec(),
+            functions,
+        };
+        csx.self_check(&named, &mut parse)?;
+        Ok((csx, sections.layout, parse.warnings))
+    }
+
+    /// Cross-checks the functions sliced out of the image against the table
+    /// they were sliced by: every named entry, at address `named[k]`, must
+    /// have become a named function starting there, and no other function
+    /// may carry a name.
+    fn self_check(&self, named: &[u32], parse: &mut Parse) -> Result<(), Error> {
+        let mut addrs = Vec::with_capacity(self.functions.len());
+        let mut addr = 0;
+        for f in &self.functions {
+            addrs.push(addr);
+            addr += f.bytecode.len() as u32;
+        }
+        for (&i, &addr) in std::iter::zip(&self.base_table, named) {
+            let f = self.functions.get(i).ok_or(Error::BadFunctionTable(addr))?;
+            if f.name.starts_with("@") || addrs[i] != addr {
+                return Err(Error::BadFunctionTable(addr));
+            }
+        }
+        let listed: HashSet<_> = self.base_table.iter().collect();
+        for (i, f) in self.functions.iter().enumerate() {
+            if !f.name.starts_with("@") && !listed.contains(&i) {
+                return Err(Error::BadFunctionTable(addrs[i]));
+            }
+        }
+        // Only bases: mods carry prefixes of these, so a short one is normal.
+        if !self.base_func.is_empty() {
+            let (global, data) = (self.global.len(), self.data.len());
+            if global.min(data) <= SWAP_HINT.0 && global.max(data) > SWAP_HINT.1 {
+                parse.warnings.push(Warning::SwappedSections(global, data));
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses with the default options, leaving any warnings unreported, as
+    /// do `parse_with_layout`, `new_with_hash` and `new_mods`.
+    pub fn new(csx: &mut &[u8]) -> Result<Self, Error> {
+        Ok(Self::parse_with_layout(csx)?.0)
+    }
+
+    /// Same as `new`, also telling where each section lay in `csx`, for
+    /// tools that edit or inspect the file in place.
+    pub fn parse_with_layout(csx: &mut &[u8]) -> Result<(Self, SectionLayout), Error> {
+        let (csx, layout, _) = Self::new_(csx, true, HashAlgo::default(), &<_>::default())?;
+        Ok((csx, layout))
+    }
+
+    pub fn new_with_hash(csx: &mut &[u8], hash_algo: HashAlgo) -> Result<Self, Error> {
+        Ok(Self::parse(csx, hash_algo, &<_>::default())?.0)
+    }
+
+    /// Parses a base by `options`, returning what it let through with a
+    /// warning for the caller to report.
+    pub fn parse(
+        csx: &mut &[u8],
+        hash_algo: HashAlgo,
+        options: &ParseOptions,
+    ) -> Result<(Self, Vec<Warning>), Error> {
+        let (csx, _, warnings) = Self::new_(csx, true, hash_algo, options)?;
+        Ok((csx, warnings))
+    }
+
+    pub fn new_mods(&self, csx: &mut &[u8]) -> Result<Self, Error> {
+        Ok(self.parse_mods(csx, &<_>::default())?.0)
+    }
+
+    /// Same as `parse`, for mods made for this base.
+    pub fn parse_mods(
+        &self,
+        csx: &mut &[u8],
+        options: &ParseOptions,
+    ) -> Result<(Self, Vec<Warning>), Error> {
+        let (mut mods, _, warnings) = Self::new_(csx, false, self.hash_algo, options)?;
+        mods.base_hash = self.base_hash;
+        Ok((mods, warnings))
+    }
+
+    pub fn set_table_order(&mut self, order: TableOrder) {
+        self.table_order = order;
+    }
+
+    /// Makes `rebuild` list the named functions in `names` first, in that
+    /// order, followed by the rest in image order.
+    pub fn set_table_names(&mut self, names: &[&str]) -> Result<(), Error> {
+        let mut table = vec![];
+        for &name in names {
+            let named = |f: &Function| f.name != "@Initialize" && f.name == name;
+            let i = self.functions.iter().position(named);
+            let i = i.expect_function(name)?;
+            table.push(i);
+        }
+        self.base_table = table;
+        self.table_order = TableOrder::Base;
+        Ok(())
+    }
+
+    pub fn set_prologue_policy(&mut self, policy: ProloguePolicy) {
+        self.prologue_policy = policy;
+    }
+
+    /// Makes `rebuild` record `tag` in a `nyandere` section, to tell later
+    /// where a patched image came from. Parsing skips that section, and the
+    /// engine is assumed to as well, since it looks sections up by name.
+    pub fn set_tag(&mut self, tag: &str) {
+        self.tag = tag.as_bytes().to_vec();
+    }
+
+    pub fn functions(&self) -> &[Function] {
+        &self.functions
+    }
+
+    pub fn base_hash(&self) -> &Hash {
+        &self.base_hash
+    }
+
+    /// The hash of this base by `hash_algo`, if it was parsed with that
+    /// algorithm as its own or in `ParseOptions::also_digest`.
+    pub fn base_hash_by(&self, hash_algo: HashAlgo) -> Option<&Hash> {
+        base_hash_by(hash_algo, (self.hash_algo, &self.base_hash), &self.digests)
+    }
+
+    pub fn global(&self) -> &[u8] {
+        &self.global
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Exchanges the `global` and `data` sections, to repair a file that has
+    /// them mixed up.
+    pub fn swap_sections(&mut self) {
+        std::mem::swap(&mut self.global, &mut self.data);
+    }
+
+    /// Replaces the `global` section, held to the same rule as the mods'
+    /// own: it has to be a prefix of the current one, and not empty, which
+    /// parsing rejects.
+    pub fn set_global(&mut self, global: Vec<u8>) -> Result<(), Error> {
+        if global.is_empty() {
+            return Err(Error::BadSection(*b"global  "));
+        }
+        if !self.global.starts_with(&global) {
+            return Err(Error::IncompatibleGlobal);
+        }
+        self.global = global;
+        Ok(())
+    }
+
+    /// Replaces the `data` section, same as `set_global`.
+    pub fn set_data(&mut self, data: Vec<u8>) -> Result<(), Error> {
+        if data.is_empty() {
+            return Err(Error::BadSection(*b"data    "));
+        }
+        if !self.data.starts_with(&data) {
+            return Err(Error::IncompatibleData);
+        }
+        self.data = data;
+        Ok(())
+    }
+
+    /// Drops `@Initialize` prologues that do nothing, as chained mods tend
+    /// to pile up. A base keeps its first one if all are empty, in case the
+    /// engine expects a prologue at all; mods may end up with none.
+    pub fn optimize_prologue(&mut self) {
+        let is_prologue = |f: &Function| f.name == "@Initialize";
+        let is_empty = |f: &Function| is_prologue(f) && f.bytecode == EMPTY_PROLOGUE;
+        let mut prologues = self.functions.iter().filter(|f| is_prologue(f));
+        let mut spare = !self.base_func.is_empty() && prologues.all(is_empty);
+
+        // Named functions stay, but may move down over dropped prologues.
+        let functions = std::mem::take(&mut self.functions);
+        let mut moved = Vec::with_capacity(functions.len());
+        for f in functions {
+            moved.push(self.functions.len());
+            if is_empty(&f) && !std::mem::take(&mut spare) {
+                continue;
+            }
+            self.functions.push(f);
+        }
+        for i in self.base_func.values_mut().chain(&mut self.base_table) {
+            *i = moved[*i];
+        }
+    }
+
+    /// Functions to lay out in the image, by `prologue_policy`, along with
+    /// their index in `functions`.
+    fn layout(&self) -> Vec<(usize, Cow<'_, Function>)> {
+        let is_prologue = |f: &Function| f.name == "@Initialize";
+        let first = self.functions.iter().position(is_prologue);
+        let last = self.functions.iter().rposition(is_prologue);
+        let mut layout = vec![];
+        for (i, f) in self.functions.iter().enumerate() {
+            if !is_prologue(f) {
+                layout.push((i, Cow::Borrowed(f)));
+                continue;
+            }
+            let keep = match self.prologue_policy {
+                ProloguePolicy::KeepAll => true,
+                ProloguePolicy::KeepFirst | ProloguePolicy::Merge => Some(i) == first,
+                ProloguePolicy::KeepLast => Some(i) == last,
+            };
+            if !keep {
+                continue;
+            }
+            if self.prologue_policy != ProloguePolicy::Merge {
+                layout.push((i, Cow::Borrowed(f)));
+                continue;
+            }
+            let mut merged = f.clone();
+            for g in self.functions[i + 1..].iter().filter(|g| is_prologue(g)) {
+                let header = 5 + extract_name(&g.bytecode, 0).unwrap().len();
+                merged.bytecode.extend_from_slice(&g.bytecode[header..]);
+            }
+            layout.push((i, Cow::Owned(merged)));
+        }
+        layout
+    }
+
+    /// Fails if two functions share a name, which the engine couldn't tell
+    /// apart in the function table.
+    pub fn rebuild(&self) -> Result<Vec<u8>, Error> {
+        let mut csx = vec![];
+        self.rebuild_to(&mut csx)?;
+        Ok(csx)
+    }
+
+    /// Like `rebuild`, but writes the image out section by section instead
+    /// of putting it together in memory first. Every size is known up front,
+    /// from the layout and the function table, so nothing is patched after.
+    pub fn rebuild_to<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        let layout = self.layout();
+        let mut addr = 0;
+        let (mut prologue, mut function) = (vec![], vec![]);
+        for &(i, ref f) in &layout {
+            if f.name == "@Initialize" {
+                prologue.push(addr);
+            } else {
+                let name = extract_name(&f.bytecode, 0).unwrap();
+                function.push((i, addr, name));
+            }
+            addr += f.bytecode.len() as u32;
+        }
+        let mut names = HashSet::default();
+        for &(i, _, name) in &function {
+            if !names.insert(name) {
+                return Err(Error::DuplicateFunction(self.functions[i].name.clone()));
+            }
+        }
+        match self.table_order {
+            TableOrder::Sorted => function.sort_by(|(_, _, f), (_, _, g)| cmp_utf16(f, g)),
+            TableOrder::Base => {
+                let n = self.functions.len();
+                let mut rank: Vec<_> = (n..2 * n).collect();
+                for (k, &i) in self.base_table.iter().enumerate() {
+                    rank[i] = k;
+                }
+                function.sort_by_key(|&(i, _, _)| rank[i]);
+            }
+        }
+        let mut table = vec![];
+        table.extend_from_slice(&(prologue.len() as u32).to_le_bytes());
+        for addr in prologue {
+            table.extend_from_slice(&addr.to_le_bytes());
+        }
+        table.extend_from_slice(&(0 as u32).to_le_bytes());
+        table.extend_from_slice(&(function.len() as u32).to_le_bytes());
+        for (_, addr, name) in function {
+            table.extend_from_slice(&addr.to_le_bytes());
+            table.extend_from_slice(&((name.len() / 2) as u32).to_le_bytes());
+            table.extend_from_slice(name);
+        }
+
+        let conststr: &[u8] = match &self.conststr[..] {
+            [] => &[0; 4],
+            conststr => conststr,
+        };
+        let linkinf: &[u8] = match &self.linkinf[..] {
+            [] => &[0; 16],
+            linkinf => linkinf,
+        };
+        let mut sections = vec![
+            (b"function", &table[..]),
+            (b"global  ", &self.global),
+            (b"data    ", &self.data),
+            (b"conststr", conststr),
+            (b"linkinf ", linkinf),
+            (TAG, &self.tag),
+        ];
+        // The tag is the only section left out when empty.
+        if self.tag.is_empty() {
+            sections.pop();
+        }
+
+        let image = addr as usize;
+        let size: usize = sections.iter().map(|(_, s)| 16 + s.len()).sum();
+        w.write_all(MAGIC)?;
+        w.write_all(&((16 + image + size) as u64).to_le_bytes())?;
+
+        w.write_all(b"image   ")?;
+        w.write_all(&(image as u64).to_le_bytes())?;
+        for (_, f) in &layout {
+            w.write_all(&f.bytecode)?;
+        }
+
+        for (name, contents) in sections {
+            w.write_all(name)?;
+            w.write_all(&(contents.len() as u64).to_le_bytes())?;
+            w.write_all(contents)?;
+        }
+        Ok(())
+    }
+
+    pub fn concat_mods(all_mods: Vec<CSX>) -> Result<CSX, Error> {
+        Self::concat_mods_with(all_mods, |_, _, _| Resolution::Error)
+    }
+
+    /// Combines two mods into one, as `concat_mods` does for a whole list:
+    /// functions of `other` follow these, and defining one twice in both is
+    /// a conflict.
+    pub fn merge(self, other: CSX) -> Result<CSX, Error> {
+        Self::concat_mods_with(vec![self, other], |_, _, _| Resolution::Error)
+    }
+
+    /// Like `concat_mods`, but asks `resolver` what to do whenever two mods
+    /// define the same function, same as `apply_all_mods_with`.
+    pub fn concat_mods_with<F>(all_mods: Vec<CSX>, mut resolver: F) -> Result<CSX, Error>
+    where
+        F: FnMut(&str, &Function, &Function) -> Resolution,
+    {
+        let capacity = all_mods.iter().map(|m| m.functions.len()).sum();
+        let mut all_mods = all_mods.into_iter();
+        let mut mods = all_mods.next().expect_mods()?;
+        let mut functions = Vec::with_capacity(capacity);
+        let mut names = HashMap::default();
+        let first = std::mem::take(&mut mods.functions);
+        push_functions(&mut functions, &mut names, first, &mut resolver)?;
+        for m in all_mods {
+            validate_same_hash(&mods, &m)?;
+
+            // Mods can only be applied if their global and data are prefixes
+            // of the base ones, and any two prefixes of the same bytes are
+            // prefixes of each other. So keeping the longer one is all the
+            // merging there is; sections that diverge could never be applied.
+            if m.global.starts_with(&mods.global) {
+                mods.global = m.global;
+            } else if !mods.global.starts_with(&m.global) {
+                return Err(Error::IncompatibleGlobal);
+            }
+
+            if m.data.starts_with(&mods.data) {
+                mods.data = m.data;
+            } else if !mods.data.starts_with(&m.data) {
+                return Err(Error::IncompatibleData);
+            }
+
+            if m.conststr.starts_with(&mods.conststr) {
+                mods.conststr = m.conststr;
+            } else if !mods.conststr.starts_with(&m.conststr) {
+                return Err(Error::IncompatibleConststr);
+            }
+
+            if m.linkinf.starts_with(&mods.linkinf) {
+                mods.linkinf = m.linkinf;
+            } else if !mods.linkinf.starts_with(&m.linkinf) {
+                return Err(Error::IncompatibleLinkinf);
+            }
+
+            push_functions(&mut functions, &mut names, m.functions, &mut resolver)?;
+        }
+
+        mods.functions = functions;
+        Ok(mods)
+    }
+
+    pub fn apply_all_mods(&mut self, mods: CSX) -> Result<(), Error> {
+        self.apply_all_mods_with(mods, |_, _, _| Resolution::Error)
+    }
+
+    /// Like `apply_all_mods`, but asks `resolver` what to do whenever a
+    /// function would be applied twice, passing the name, the function that
+    /// was applied first and the one that collides with it.
+    pub fn apply_all_mods_with<F>(&mut self, mods: CSX, mut resolver: F) -> Result<(), Error>
+    where
+        F: FnMut(&str, &Function, &Function) -> Resolution,
+    {
+        validate_same_hash(self, &mods)?;
+        validate_items_same_prefix(self, &mods)?;
+
+        self.global = mods.global;
+        self.data = mods.data;
+        // Unlike global and data, mods may extend the pool and the links,
+        // and mods that don't touch them at all have none.
+        if mods.conststr.len() > self.conststr.len() {
+            self.conststr = mods.conststr;
+        }
+        if mods.linkinf.len() > self.linkinf.len() {
+            self.linkinf = mods.linkinf;
+        }
+        for f in mods.functions {
+            if f.name.starts_with("@") {
+                if f.name != "@Initialize" {
+                    return Err(Error::BadFunctionName);
+                }
+                self.functions.push(f);
+                continue;
+            }
+
+            let index = self.base_func.get(&f.name).copied();
+            if !self.mods_used.insert(f.name.clone()) {
+                let index = index.or_else(|| self.functions.iter().rposition(|g| g.name == f.name));
+                let index = index.expect("applied function is missing");
+                match resolver(&f.name, &self.functions[index], &f) {
+                    Resolution::KeepOld => (),
+                    Resolution::KeepNew => self.functions[index] = f,
+                    Resolution::Error => return Err(Error::ModsConflicts(f.name)),
+                }
+                continue;
+            }
+
+            if let Some(index) = index {
+                self.functions[index] = f;
+            } else {
+                self.functions.push(f);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Splits mods into one mods per named function, each carrying the same
+    /// global and data. Prologues have no name to pick them by, so they are
+    /// left out.
+    pub fn split(&self) -> Vec<CSX> {
+        let named = self.functions.iter().filter(|f| !f.name.starts_with("@"));
+        named
+            .map(|f| CSX {
+                base_hash: self.base_hash,
+                hash_algo: self.hash_algo,
+                digests: vec![],
+                base_func: <_>::default(),
+                base_table: vec![],
+                table_order: <_>::default(),
+                prologue_policy: <_>::default(),
+                tag: vec![],
+                mods_used: <_>::default(),
+                global: self.global.clone(),
+                data: self.data.clone(),
+                conststr: self.conststr.clone(),
+                linkinf: self.linkinf.clone(),
+                functions: vec![f.clone()],
+            })
+            .collect()
+    }
+
+    /// Moves these mods over to `new_base`, e.g. an updated version of the
+    /// game: compressing them afterwards diffs against the new base's
+    /// functions. Their global and data must suit the new base as well.
+    pub fn rebase(&mut self, new_base: &CSX) -> Result<(), Error> {
+        validate_items_same_prefix(new_base, self)?;
+        self.base_hash = new_base.base_hash;
+        self.hash_algo = new_base.hash_algo;
+        Ok(())
+    }
+
+    /// Reverts `mods` from this patched image: every function it overrode is
+    /// restored to its `base` version, every function and prologue it added
+    /// is removed, and global/data go back to the base ones.
+    pub fn unapply(&mut self, base: &CSX, mods: &CSX) -> Result<(), Error> {
+        validate_same_hash(base, mods)?;
+
+        let mut removed = vec![false; self.functions.len()];
+        for f in &mods.functions {
+            if f.name.starts_with("@") {
+                let index = self.functions.iter().enumerate().rposition(|(i, g)| {
+                    !removed[i] && g.name == f.name && g.bytecode == f.bytecode
+                });
+                if let Some(index) = index {
+                    removed[index] = true;
+                }
+                continue;
+            }
+
+            let index = self.functions.iter().position(|g| g.name == f.name);
+            let index = index.expect_function(&f.name)?;
+            match base.base_func.get(&f.name) {
+                Some(&i) => self.functions[index] = base.functions[i].clone(),
+                None => removed[index] = true,
+            }
+            self.mods_used.remove(&f.name);
+        }
+
+        // Same as `optimize_prologue`, what's left moves down over what goes.
+        let (mut moved, mut kept) = (Vec::with_capacity(removed.len()), 0);
+        for &removed in &removed {
+            moved.push((!removed).then_some(kept));
+            kept += !removed as usize;
+        }
+        self.base_table = self.base_table.iter().filter_map(|&i| moved[i]).collect();
+        let mut removed = removed.into_iter();
+        self.functions.retain(|_| !removed.next().unwrap());
+        if !self.base_func.is_empty() {
+            self.base_func = self
+                .functions
+                .iter()
+                .enumerate()
+                .filter(|(_, f)| !f.name.starts_with("@"))
+                .map(|(i, f)| (f.name.clone(), i))
+                .collect();
+        }
+        self.global = base.global.clone();
+        self.data = base.data.clone();
+        self.conststr = base.conststr.clone();
+        self.linkinf = base.linkinf.clone();
+
+        Ok(())
+    }
+
+    /// Offset of the first byte where the mods' `global` section stops being
+    /// a prefix of this one, if it does.
+    pub fn global_divergence(&self, mods: &CSX) -> Option<usize> {
+        divergence(&self.global, &mods.global)
+    }
+
+    /// Offset of the first byte where the mods' `data` section stops being
+    /// a prefix of this one, if it does. This compares bytes only: the layout
+    /// of the variables stored in `data` isn't parsed, so a mod that changes
+    /// a variable but stays a prefix passes unnoticed.
+    pub fn data_divergence(&self, mods: &CSX) -> Option<usize> {
+        divergence(&self.data, &mods.data)
+    }
+
+    pub fn classify_mod(&self, mods: &CSX) -> ModTargets {
+        let mut targets = ModTargets::default();
+        for f in &mods.functions {
+            if f.name.starts_with("@") {
+                targets.prologues += 1;
+            } else if self.base_func.contains_key(&f.name) {
+                targets.overridden.push(f.name.clone());
+            } else {
+                targets.added.push(f.name.clone());
+            }
+        }
+        targets
+    }
+
+    /// Compares named functions by bytecode. Prologues are only counted, as
+    /// applying `other` appends them instead of replacing any. Lists are
+    /// sorted by name. Only a full image `other` removes functions: mods
+    /// leave whatever they don't define alone.
+    pub fn diff(&self, other: &CSX) -> CsxDiff {
+        fn named(csx: &CSX) -> HashMap<&String, &Vec<u8>> {
+            csx.functions
+                .iter()
+                .filter(|f| !f.name.starts_with("@"))
+                .map(|f| (&f.name, &f.bytecode))
+                .collect()
+        }
+        let (this, that) = (named(self), named(other));
+
+        let is_prologue = |f: &&Function| f.name == "@Initialize";
+        let mut diff = CsxDiff {
+            prologues: other.functions.iter().filter(is_prologue).count(),
+            global_changed: self.global != other.global,
+            data_changed: self.data != other.data,
+            ..<_>::default()
+        };
+        for (&name, &bytecode) in &that {
+            match this.get(name) {
+                None => diff.added.push(name.clone()),
+                Some(&old) if old != bytecode => diff.modified.push(name.clone()),
+                Some(_) => {}
+            }
+        }
+        if !other.base_func.is_empty() {
+            for &name in this.keys() {
+                if !that.contains_key(name) {
+                    diff.removed.push(name.clone());
+                }
+            }
+        }
+        diff.added.sort_unstable();
+        diff.removed.sort_unstable();
+        diff.modified.sort_unstable();
+        diff
+    }
+
+    /// Mods that turn this base into `modified`, a full image with this one's
+    /// functions changed and added, matched by name whatever its own hash.
+    /// Prologues past the base's count are taken as added. Functions the
+    /// image lacks are not removed, which mods cannot express.
+    pub fn derive_mods(&self, modified: &CSX) -> CSX {
+        let diff = self.diff(modified);
+        let changed: HashSet<_> = diff.added.iter().chain(&diff.modified).collect();
+        let is_prologue = |f: &&Function| f.name == "@Initialize";
+        let prologues = self.functions.iter().filter(is_prologue).count();
+        let added_prologues = modified
+            .functions
+            .iter()
+            .filter(is_prologue)
+            .skip(prologues);
+        let functions = modified
+            .functions
+            .iter()
+            .filter(|f| changed.contains(&f.name))
+            .chain(added_prologues)
+            .cloned()
+            .collect();
+        CSX {
+            base_hash: self.base_hash,
+            hash_algo: self.hash_algo,
+            digests: vec![],
+            base_func: <_>::default(),
+            base_table: vec![],
+            table_order: <_>::default(),
+            prologue_policy: <_>::default(),
+            tag: vec![],
+            mods_used: <_>::default(),
+            global: modified.global.clone(),
+            data: modified.data.clone(),
+            conststr: modified.conststr.clone(),
+            linkinf: modified.linkinf.clone(),
+            functions,
+        }
+    }
+
+    /// Base functions the mods leave alone, sorted by name: the complement
+    /// of `classify_mod`'s `overridden`. Empty for mods authored as complete
+    /// images rather than as a set of changes. For a full image, rather than
+    /// mods, this is what `diff` lists as removed.
+    pub fn untouched_by(&self, mods: &CSX) -> Vec<String> {
+        let defined: HashSet<_> = mods.functions.iter().map(|f| &f.name).collect();
+        let mut missing: Vec<_> = self
+            .base_func
+            .keys()
+            .filter(|name| !defined.contains(name))
+            .cloned()
+            .collect();
+        missing.sort_unstable();
+        missing
+    }
+
+    /// Fails on the first function larger than `max` bytes, a guard against
+    /// corrupt or hostile mods.
+    pub fn validate_function_size(&self, max: usize) -> Result<(), Error> {
+        match self.functions.iter().find(|f| f.bytecode.len() > max) {
+            Some(f) => Err(Error::FunctionTooLarge(f.name.clone(), f.bytecode.len())),
+            None => Ok(()),
+        }
+    }
+
+    /// Puts `bytecode`, e.g. taken from another image, in place of the
+    /// function `name`, or adds it if there is none. The bytecode has to
+    /// open with that same name.
+    pub fn transplant(&mut self, name: &str, bytecode: Vec<u8>) -> Result<(), Error> {
+        if name.starts_with("@") || extract_name(&bytecode, 0)? != to_utf16(name) {
+            return Err(Error::BadFunctionName);
+        }
+
+        let f = Function {
+            name: name.into(),
+            bytecode,
+        };
+        match self.functions.iter().position(|g| g.name == name) {
+            Some(index) => self.functions[index] = f,
+            None => self.functions.push(f),
+        }
+        Ok(())
+    }
+
+    pub fn rename_function(&mut self, from: &str, to: &str) -> Result<(), Error> {
+        if from.starts_with("@") || to.starts_with("@") {
+            return Err(Error::BadFunctionName);
+        }
+
+        if self.functions.iter().any(|f| f.name == to) {
+            return Err(Error::FunctionExists(to.into()));
+        }
+
+        let index = self.functions.iter().position(|f| f.name == from);
+        let index = index.expect_function(from)?;
+        let f = &mut self.functions[index];
+        let old_len = 5 + extract_name(&f.bytecode, 0)?.len();
+        let new_name = to_utf16(to);
+        let mut bytecode = Vec::with_capacity(f.bytecode.len() - old_len + 5 + new_name.len());
+        bytecode.push(4u8);
+        bytecode.extend_from_slice(&((new_name.len() / 2) as u32).to_le_bytes());
+        bytecode.extend_from_slice(&new_name);
+        bytecode.extend_from_slice(&f.bytecode[old_len..]);
+        f.bytecode = bytecode;
+        f.name = to.into();
+
+        if let Some(base_index) = self.base_func.remove(from) {
+            self.base_func.insert(to.into(), base_index);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Resolution {
+    KeepOld,
+    KeepNew,
+    #[default]
+    Error,
+}
+
+impl std::str::FromStr for Resolution {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep-old" => Ok(Resolution::KeepOld),
+            "keep-new" => Ok(Resolution::KeepNew),
+            "error" => Ok(Resolution::Error),
+            _ => Err("expected `keep-old`, `keep-new` or `error`"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ModTargets {
+    pub overridden: Vec<String>,
+    pub added: Vec<String>,
+    pub prologues: usize,
+}
 
-pub struct CSX {
+#[derive(Debug, Default)]
+pub struct CsxDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<String>,
+    pub prologues: usize,
+    pub global_changed: bool,
+    pub data_changed: bool,
+}
+
+/// Lightweight view of a base, indexing functions by name without copying
+/// their bytecode out of the image.
+pub struct BaseIndex<'a> {
     base_hash: Hash,
-    base_func: HashMap<String, usize>,
-    mods_used: HashSet<String>,
-    global: Vec<u8>,
-    data: Vec<u8>,
-    functions: Vec<Function>,
+    hash_algo: HashAlgo,
+    digests: Vec<(HashAlgo, Hash)>,
+    base_func: HashMap<String, Range<usize>>,
+    image: &'a [u8],
+    global: &'a [u8],
+    data: &'a [u8],
+    conststr: &'a [u8],
+    linkinf: &'a [u8],
 }
 
-impl CSX {
-    fn new_(csx: &mut &[u8], base: bool) -> Result<Self, Error> {
-        let base_hash = if base { sha3_224(csx) } else { <_>::default() };
+impl<'a> BaseIndex<'a> {
+    pub fn new(csx: &mut &'a [u8], hash_algo: HashAlgo) -> Result<Self, Error> {
+        Ok(Self::parse(csx, hash_algo, &<_>::default())?.0)
+    }
+
+    /// Same as `CSX::parse`, for the index.
+    pub fn parse(
+        csx: &mut &'a [u8],
+        hash_algo: HashAlgo,
+        options: &ParseOptions,
+    ) -> Result<(Self, Vec<Warning>), Error> {
+        let base_hash = hash_algo.digest(csx);
+        let digests = other_digests(csx, hash_algo, options);
+        let mut parse = Parse::new(options);
+        let sections = Sections::new(csx, true, &mut parse)?;
+
+        let mut base_func = HashMap::default();
+        let (addr_splits, _) = sections.addrs(&parse)?;
+        for range in addr_splits.windows(2) {
+            let name = extract_name(sections.image, range[0])?;
+            let (name, warning) = from_utf16(name, range[0], options.strict_utf16)?;
+            parse.warnings.extend(warning);
+            if !name.starts_with("@") {
+                base_func.insert(name, range[0] as usize..range[1] as usize);
+            }
+        }
+
+        let index = Self {
+            base_hash,
+            hash_algo,
+            digests,
+            base_func,
+            image: sections.image,
+            global: sections.global,
+            data: sections.data,
+            conststr: sections.conststr,
+            linkinf: sections.linkinf,
+        };
+        Ok((index, parse.warnings))
+    }
+
+    /// Same as `CSX::base_hash_by`.
+    pub fn base_hash_by(&self, hash_algo: HashAlgo) -> Option<&Hash> {
+        base_hash_by(hash_algo, (self.hash_algo, &self.base_hash), &self.digests)
+    }
+
+    pub fn function(&self, name: &str) -> Option<&'a [u8]> {
+        let range = self.base_func.get(name)?;
+        Some(&self.image[range.clone()])
+    }
+}
+
+struct Sections<'a> {
+    base: bool,
+    layout: SectionLayout,
+    image: &'a [u8],
+    function: &'a [u8],
+    global: &'a [u8],
+    data: &'a [u8],
+    conststr: &'a [u8],
+    linkinf: &'a [u8],
+}
+
+impl<'a> Sections<'a> {
+    /// Sections may come in any order: they are only collected here, and the
+    /// function table is validated against `image` later on, in `addrs`.
+    fn new(csx: &mut &'a [u8], base: bool, parse: &mut Parse) -> Result<Self, Error> {
+        let total = csx.len();
         let header = csx.split_off(..64).expect_eof()?;
-        let _length = header.strip_prefix(MAGIC).expect_magic()?;
+        // The last 8 bytes of the header hold the size of all sections that
+        // follow it, not counting trailing alignment padding. Zero means the
+        // writer left it unset, and then the sections run to the end, as
+        // they do when it doesn't match the file, with a warning. A length
+        // a little short may well land in zeros that belong to a section,
+        // so the sections have to end right there for the rest to be padding.
+        let length = header.strip_prefix(MAGIC).expect_magic()?;
+        let mut length = u64::from_le_bytes(length.try_into().unwrap());
+        let at = usize::try_from(length).unwrap_or(usize::MAX);
+        let padding = match csx.get(..at).zip(csx.get(at..)) {
+            _ if length == 0 => &[][..],
+            Some((sections, tail))
+                if tail.iter().all(|&byte| byte == 0) && whole_sections(sections) =>
+            {
+                csx.split_off(at..).unwrap()
+            }
+            _ => {
+                let actual = csx.len() as u64;
+                parse.warnings.push(Warning::HeaderLength(length, actual));
+                length = 0;
+                &[][..]
+            }
+        };
+        trace!(
+            parse,
+            "trace: header length {length}, {} bytes of padding",
+            padding.len()
+        );
 
         let [
             mut image,
@@ -60,11 +1177,29 @@ impl CSX {
             mut linkinf,
         ] = <_>::default();
 
+        let mut layout = vec![];
         while !csx.is_empty() {
+            // Trailing zeros are alignment padding, not another section.
+            if length == 0 && csx.iter().all(|&byte| byte == 0) {
+                trace!(parse, "trace: {} bytes of trailing padding", csx.len());
+                break;
+            }
+
+            let offset = total - csx.len();
             let header = csx.split_off_chunk()?;
             let length = csx.split_off_chunk()?;
             let length = u64::from_le_bytes(length) as usize;
+            trace!(
+                parse,
+                "trace: section `{}` at offset {offset}, length {length}",
+                header.escape_ascii()
+            );
             let contents = csx.split_off(..length).expect_eof()?;
+            layout.push(SectionInfo {
+                name: header,
+                offset: offset as u64,
+                length: length as u64,
+            });
             match &header {
                 b"image   " => image = contents,
                 b"function" => function = contents,
@@ -72,10 +1207,18 @@ impl CSX {
                 b"data    " => data = contents,
                 b"conststr" => conststr = contents,
                 b"linkinf " => linkinf = contents,
+                TAG => (),
                 _ => return Err(Error::UnknownSection(header)),
             }
         }
 
+        if function.is_empty() {
+            return Err(Error::EmptyFunctionSection);
+        }
+
+        // Required of mods as much as of the base: apply replaces the base's
+        // global and data with the mods' own, so empty ones would wipe them
+        // out rather than leave the base's in place.
         if global.is_empty() {
             return Err(Error::BadSection(*b"global  "));
         }
@@ -84,20 +1227,37 @@ impl CSX {
             return Err(Error::BadSection(*b"data    "));
         }
 
-        if !conststr.is_empty() && conststr != [0; 4] {
-            return Err(Error::BadSection(*b"conststr"));
+        // The stubs every file is known to carry, as good as none.
+        if conststr == [0; 4] {
+            conststr = &[];
         }
-
-        if !linkinf.is_empty() && linkinf != [0; 16] && base {
-            return Err(Error::BadSection(*b"linkinf "));
+        if linkinf == [0; 16] {
+            linkinf = &[];
         }
 
-        let mut addr_splits = vec![];
+        Ok(Self {
+            base,
+            layout,
+            image,
+            function,
+            global,
+            data,
+            conststr,
+            linkinf,
+        })
+    }
+
+    /// Sorted addresses of every function, followed by the end of the image,
+    /// and addresses of named functions in the order the table lists them.
+    fn addrs(&self, parse: &Parse) -> Result<(Vec<u32>, Vec<u32>), Error> {
+        let (image, mut function) = (self.image, self.function);
+        let (mut addr_splits, mut named) = (vec![], vec![]);
 
         let length = function.split_off_chunk()?;
         for _ in 0..u32::from_le_bytes(length) {
             let addr = function.split_off_chunk()?;
             let addr = u32::from_le_bytes(addr);
+            trace!(parse, "trace: prologue address {addr:#x}");
             validate_name(image, addr, PROLOGUE)?;
             addr_splits.push(addr);
         }
@@ -114,185 +1274,269 @@ impl CSX {
             let len = function.split_off_chunk()?;
             let len = u32::from_le_bytes(len) as usize;
             let name = function.split_off(..2 * len).expect_eof()?;
+            trace!(
+                parse,
+                "trace: function address {addr:#x}, name `{}`",
+                String::from_utf16le_lossy(name)
+            );
             validate_name(image, addr, name)?;
             if name.starts_with(b"@\0") {
                 return Err(Error::BadFunctionName);
             }
             addr_splits.push(addr);
+            named.push(addr);
+        }
+
+        // Mods may consist of prologues alone, but a base without named
+        // functions has nothing for mods to override.
+        if self.base && named.is_empty() {
+            return Err(Error::NoNamedFunctions);
         }
 
         addr_splits.sort_unstable();
         addr_splits.push(image.len() as u32);
-        for i in 0..addr_splits.len() - 1 {
-            addr_splits[i] = addr_splits[i + 1] - addr_splits[i];
-        }
-        addr_splits.pop();
 
-        let mut functions = Vec::with_capacity(addr_splits.len());
-        for size in addr_splits {
-            let name = extract_name(image, 0)?;
-            let name = from_utf16(name)?;
-            let bytecode = image.split_off(..size as usize).expect_eof()?.to_vec();
-            functions.push(Function { name, bytecode });
+        // Sizes are taken from the deltas between sorted addresses, so
+        // functions must partition `image` exactly: the first one starts
+        // at 0 and no two share an address.
+        if addr_splits[0] != 0 {
+            return Err(Error::BadFunctionTable(addr_splits[0]));
+        }
+        if let Some(pair) = addr_splits.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(Error::BadFunctionTable(pair[0]));
         }
+        Ok((addr_splits, named))
+    }
+}
 
-        let base_func = if base {
-            functions
-                .iter()
-                .enumerate()
-                .filter(|(_, f)| !f.name.starts_with("@"))
-                .map(|(i, f)| (f.name.clone(), i))
-                .collect()
-        } else {
-            <_>::default()
-        };
+/// Where a section lies in a CSX file, as found by `scan_sections`, or by
+/// `CSX::parse_with_layout`. The offset is that of its 16-byte header.
+#[derive(Debug)]
+pub struct SectionInfo {
+    pub name: [u8; 8],
+    pub offset: u64,
+    pub length: u64,
+}
 
-        Ok(Self {
-            base_hash,
-            base_func,
-            mods_used: <_>::default(),
-            global: global.to_vec(),
-            data: data.to_vec(),
-            functions,
-        })
-    }
+/// Every section of a CSX file, in file order.
+pub type SectionLayout = Vec<SectionInfo>;
 
-    pub fn new(csx: &mut &[u8]) -> Result<Self, Error> {
-        Self::new_(csx, true)
+/// Lists the sections of a CSX from their headers alone, seeking over their
+/// contents, so inspecting a multi-gigabyte image takes a few small reads.
+/// Nothing is validated beyond the magic and the headers themselves.
+pub fn scan_sections(csx: &mut (impl Read + Seek)) -> Result<Vec<SectionInfo>, Error> {
+    let size = csx.seek(SeekFrom::End(0))?;
+    csx.seek(SeekFrom::Start(0))?;
+    let mut header = [0; 64];
+    csx.read_exact(&mut header).map_err(eof)?;
+    let length = header.strip_prefix(MAGIC).expect_magic()?;
+    let length = u64::from_le_bytes(length.try_into().unwrap());
+    // Same as `Sections::new`, a length the sections don't fit is ignored.
+    if length != 0 && length <= size - 64 {
+        if let Ok(sections) = scan_until(csx, 64 + length, false) {
+            return Ok(sections);
+        }
+        csx.seek(SeekFrom::Start(64))?;
     }
+    scan_until(csx, size, true)
+}
 
-    pub fn new_mods(&self, csx: &mut &[u8]) -> Result<Self, Error> {
-        let mut mods = Self::new_(csx, false)?;
-        mods.base_hash = self.base_hash;
-        Ok(mods)
+/// Whether `sections` is made of whole sections, the last one ending with it.
+fn whole_sections(mut sections: &[u8]) -> bool {
+    while !sections.is_empty() {
+        let Ok(header) = sections.split_off_chunk::<16>() else {
+            return false;
+        };
+        let length = u64::from_le_bytes(header[8..].try_into().unwrap());
+        let length = usize::try_from(length).unwrap_or(usize::MAX);
+        let Some(rest) = sections.get(length..) else {
+            return false;
+        };
+        sections = rest;
     }
+    true
+}
 
-    // pub fn optimize_prologue(&mut self) {
-    //     self.functions
-    //         .retain(|f| f.name != "@Initialize" || f.bytecode != EMPTY_PROLOGUE);
-    // }
+/// Section headers from the current offset, past the file header, up to
+/// `end`, stopping early at trailing zeros if `padded`.
+fn scan_until(
+    csx: &mut (impl Read + Seek),
+    end: u64,
+    padded: bool,
+) -> Result<Vec<SectionInfo>, Error> {
+    let mut sections = vec![];
+    let mut offset = 64;
+    while offset < end {
+        let mut header = [0; 16];
+        csx.read_exact(&mut header).map_err(eof)?;
+        // Same as `Sections::new`, trailing zeros are alignment padding.
+        if padded && header == [0; 16] {
+            break;
+        }
+        let (name, length) = header.split_at(8);
+        let length = u64::from_le_bytes(length.try_into().unwrap());
+        let next = (offset + 16).checked_add(length);
+        let next = next.filter(|&next| next <= end).expect_eof()?;
+        sections.push(SectionInfo {
+            name: name.try_into().unwrap(),
+            offset,
+            length,
+        });
+        csx.seek(SeekFrom::Start(next))?;
+        offset = next;
+    }
+    Ok(sections)
+}
 
-    pub fn rebuild(&self) -> Vec<u8> {
-        let mut csx = vec![];
-        csx.extend_from_slice(MAGIC);
-        csx.extend_from_slice(&[0; 8]);
+/// One of the rules `CSX::new` enforces, as audited by `check_compat`.
+pub struct CompatCheck {
+    pub rule: &'static str,
+    pub result: Result<(), Error>,
+}
 
-        csx.extend_from_slice(b"image   ");
-        let origin = csx.len();
-        csx.extend_from_slice(&[0; 8]);
-        for f in &self.functions {
-            csx.extend_from_slice(&f.bytecode);
+/// Runs every rule a base (or mods, if not `base`) is held to and reports
+/// each outcome, where parsing stops at the first broken one. Rules on the
+/// sections' contents need the section headers to be readable, though.
+pub fn check_compat(csx: &[u8], base: bool) -> Vec<CompatCheck> {
+    let mut checks = vec![];
+    let mut check = |rule, result| checks.push(CompatCheck { rule, result });
+    let sections = scan_sections(&mut std::io::Cursor::new(csx));
+    let rule = "header and section headers are well-formed";
+    let sections = match sections {
+        Ok(sections) => sections,
+        Err(err) => {
+            check(rule, Err(err));
+            return checks;
         }
-        let size = csx.len() - origin - 8;
-        csx[origin..origin + 8].copy_from_slice(&(size as u64).to_le_bytes());
+    };
+    check(rule, Ok(()));
 
-        csx.extend_from_slice(b"function");
-        let origin = csx.len();
-        csx.extend_from_slice(&[0; 8]);
-        let mut addr = 0;
-        let (mut prologue, mut function) = (vec![], vec![]);
-        for f in &self.functions {
-            if f.name == "@Initialize" {
-                prologue.push(addr);
-            } else {
-                let name = extract_name(&f.bytecode, 0).unwrap();
-                function.push((addr, name));
-            }
-            addr += f.bytecode.len() as u32;
-        }
-        function.sort_by(|(_, f), (_, g)| cmp_utf16(f, g));
-        csx.extend_from_slice(&(prologue.len() as u32).to_le_bytes());
-        for addr in prologue {
-            csx.extend_from_slice(&addr.to_le_bytes());
-        }
-        csx.extend_from_slice(&(0 as u32).to_le_bytes());
-        csx.extend_from_slice(&(function.len() as u32).to_le_bytes());
-        for (addr, name) in function {
-            csx.extend_from_slice(&addr.to_le_bytes());
-            csx.extend_from_slice(&((name.len() / 2) as u32).to_le_bytes());
-            csx.extend_from_slice(name);
+    let section = |name: &[u8; 8]| match sections.iter().find(|s| &s.name == name) {
+        Some(s) => &csx[s.offset as usize + 16..][..s.length as usize],
+        None => &[],
+    };
+    let non_empty = |name: &[u8; 8]| {
+        if section(name).is_empty() {
+            Err(Error::BadSection(*name))
+        } else {
+            Ok(())
         }
-        let size = csx.len() - origin - 8;
-        csx[origin..origin + 8].copy_from_slice(&(size as u64).to_le_bytes());
-
-        csx.extend_from_slice(b"global  ");
-        csx.extend_from_slice(&(self.global.len() as u64).to_le_bytes());
-        csx.extend_from_slice(&self.global);
-
-        csx.extend_from_slice(b"data    ");
-        csx.extend_from_slice(&(self.data.len() as u64).to_le_bytes());
-        csx.extend_from_slice(&self.data);
+    };
 
-        csx.extend_from_slice(b"conststr");
-        csx.extend_from_slice(&(4u64).to_le_bytes());
-        csx.extend_from_slice(&(0u32).to_le_bytes());
+    let known = [
+        b"image   ",
+        b"function",
+        b"global  ",
+        b"data    ",
+        b"conststr",
+        b"linkinf ",
+        TAG,
+    ];
+    let unknown = sections.iter().find(|s| !known.contains(&&s.name));
+    let result = unknown.map_or(Ok(()), |s| Err(Error::UnknownSection(s.name)));
+    check("only known sections are present", result);
+    let result = match section(b"function") {
+        [] => Err(Error::EmptyFunctionSection),
+        _ => Ok(()),
+    };
+    check("the function section is not empty", result);
+    let rule = "the global section is not empty, apply takes it from the mods";
+    check(rule, non_empty(b"global  "));
+    let rule = "the data section is not empty, apply takes it from the mods";
+    check(rule, non_empty(b"data    "));
 
-        csx.extend_from_slice(b"linkinf ");
-        csx.extend_from_slice(&(16u64).to_le_bytes());
-        for _ in 0..4 {
-            csx.extend_from_slice(&(0u32).to_le_bytes());
-        }
+    let table = Sections {
+        base,
+        layout: vec![],
+        image: section(b"image   "),
+        function: section(b"function"),
+        global: section(b"global  "),
+        data: section(b"data    "),
+        conststr: section(b"conststr"),
+        linkinf: section(b"linkinf "),
+    };
+    let rule = "the function table has no epilogue, only `@Initialize` prologues, \
+                names every function at its address and partitions the image";
+    let options = ParseOptions::default();
+    check(rule, table.addrs(&Parse::new(&options)).map(|_| ()));
+    checks
+}
 
-        let size = csx.len() - 64;
-        csx[56..64].copy_from_slice(&(size as u64).to_le_bytes());
-        csx
+fn eof(err: std::io::Error) -> Error {
+    match err.kind() {
+        std::io::ErrorKind::UnexpectedEof => Error::UnexpectedEof,
+        _ => Error::IO(err),
     }
+}
 
-    pub fn concat_mods(all_mods: Vec<CSX>) -> Result<CSX, Error> {
-        let mut all_mods = all_mods.into_iter();
-        let mut mods = all_mods.next().expect_mods()?;
-        for m in all_mods {
-            validate_same_hash(&mods, &m)?;
+pub fn read_file(path: &Path) -> Result<Vec<u8>, Error> {
+    Ok(std::fs::read(path).context(path)?)
+}
 
-            if m.global.starts_with(&mods.global) {
-                mods.global = m.global;
-            } else if !mods.global.starts_with(&m.global) {
-                return Err(Error::IncompatibleGlobal);
-            }
+/// Writes `contents` to `path`, returning the SHA3-224 of the bytes written.
+pub fn write_file(path: &Path, contents: &[u8]) -> Result<Hash, Error> {
+    let file = std::fs::File::create(path).context(path)?;
+    let mut w = HashWriter::new(file);
+    w.write_all(contents).context(path)?;
+    w.flush().context(path)?;
+    Ok(w.finalize())
+}
 
-            if m.data.starts_with(&mods.data) {
-                mods.data = m.data;
-            } else if !mods.data.starts_with(&m.data) {
-                return Err(Error::IncompatibleData);
-            }
+/// Rebuilds `csx` straight into `path`, returning the SHA3-224 and the count
+/// of the bytes written.
+pub fn write_csx(path: &Path, csx: &CSX) -> Result<(Hash, usize), Error> {
+    let file = std::fs::File::create(path).context(path)?;
+    let mut w = HashWriter::new(std::io::BufWriter::new(file));
+    csx.rebuild_to(&mut w).map_err(|err| match err {
+        Error::IO(err) => Error::FileIO(path.to_path_buf(), err),
+        err => err,
+    })?;
+    w.flush().context(path)?;
+    let written = w.written();
+    Ok((w.finalize(), written))
+}
 
-            mods.functions.append(&mut { m.functions });
+/// Hashes everything written through it, so no second pass is needed to
+/// fingerprint the output.
+pub struct HashWriter<W> {
+    inner: W,
+    hasher: Sha3_224,
+    written: usize,
+}
+
+impl<W: Write> HashWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha3_224::new(),
+            written: 0,
         }
+    }
 
-        Ok(mods)
+    /// Bytes written through so far.
+    pub fn written(&self) -> usize {
+        self.written
     }
 
-    pub fn apply_all_mods(&mut self, mods: CSX) -> Result<(), Error> {
-        validate_same_hash(self, &mods)?;
-        validate_items_same_prefix(self, &mods)?;
+    pub fn finalize(self) -> Hash {
+        self.hasher.finalize().into()
+    }
+}
 
-        self.global = mods.global;
-        self.data = mods.data;
-        for f in mods.functions {
-            if f.name.starts_with("@") {
-                if f.name != "@Initialize" {
-                    return Err(Error::BadFunctionName);
-                }
-                self.functions.push(f);
-                continue;
-            }
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.written += n;
+        Ok(n)
+    }
 
-            if !self.mods_used.insert(f.name.clone()) {
-                return Err(Error::ModsConflicts(f.name));
-            }
-            
-            if let Some(&index) = self.base_func.get(&f.name) {
-                self.functions[index] = f;
-            } else {
-                self.functions.push(f);
-            }
-        }
-        
-        Ok(())
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
     }
 }
 
-fn sha3_224(data: &[u8]) -> Hash {
+pub fn sha3_224(data: &[u8]) -> Hash {
     let mut hasher = Sha3_224::new();
     hasher.update(data);
     hasher.finalize().into()
@@ -316,8 +1560,27 @@ fn extract_name(image: &[u8], addr: u32) -> Result<&[u8], Error> {
     start.get(..len).expect_addr()
 }
 
+fn other_digests(csx: &[u8], hash_algo: HashAlgo, options: &ParseOptions) -> Vec<(HashAlgo, Hash)> {
+    let others = options.also_digest.iter().copied();
+    let others = others.filter(|&algo| algo != hash_algo);
+    others.map(|algo| (algo, algo.digest(csx))).collect()
+}
+
+fn base_hash_by<'h>(
+    hash_algo: HashAlgo,
+    own: (HashAlgo, &'h Hash),
+    digests: &'h [(HashAlgo, Hash)],
+) -> Option<&'h Hash> {
+    let others = digests.iter().map(|(algo, hash)| (*algo, hash));
+    let mut all = std::iter::once(own).chain(others);
+    let (_, hash) = all.find(|&(algo, _)| algo == hash_algo)?;
+    Some(hash)
+}
+
+/// Mods made with another algorithm than the base's own still match it by
+/// the base's digest under theirs.
 fn validate_same_hash(base: &CSX, mods: &CSX) -> Result<(), Error> {
-    if base.base_hash != mods.base_hash {
+    if base.base_hash_by(mods.hash_algo) != Some(&mods.base_hash) {
         return Err(Error::HashMismatch);
     }
 
@@ -333,11 +1596,74 @@ fn validate_items_same_prefix(base: &CSX, mods: &CSX) -> Result<(), Error> {
         return Err(Error::IncompatibleData);
     }
 
+    let (base_pool, mods_pool) = (&base.conststr, &mods.conststr);
+    if !base_pool.starts_with(mods_pool) && !mods_pool.starts_with(base_pool) {
+        return Err(Error::IncompatibleConststr);
+    }
+
+    let (base_links, mods_links) = (&base.linkinf, &mods.linkinf);
+    if !base_links.starts_with(mods_links) && !mods_links.starts_with(base_links) {
+        return Err(Error::IncompatibleLinkinf);
+    }
+
+    Ok(())
+}
+
+fn push_functions<F>(
+    functions: &mut Vec<Function>,
+    names: &mut HashMap<String, usize>,
+    new: Vec<Function>,
+    resolver: &mut F,
+) -> Result<(), Error>
+where
+    F: FnMut(&str, &Function, &Function) -> Resolution,
+{
+    for f in new {
+        if f.name.starts_with("@") {
+            functions.push(f);
+            continue;
+        }
+
+        if let Some(&index) = names.get(&f.name) {
+            match resolver(&f.name, &functions[index], &f) {
+                Resolution::KeepOld => (),
+                Resolution::KeepNew => functions[index] = f,
+                Resolution::Error => return Err(Error::ModsConflicts(f.name)),
+            }
+            continue;
+        }
+
+        names.insert(f.name.clone(), functions.len());
+        functions.push(f);
+    }
+
     Ok(())
 }
 
-fn from_utf16(bytes: &[u8]) -> Result<String, Error> {
-    String::from_utf16le(bytes).map_err(|_| Error::DecodeUtf16)
+fn divergence(base: &[u8], mods: &[u8]) -> Option<usize> {
+    if base.starts_with(mods) {
+        return None;
+    }
+    let at = std::iter::zip(base, mods).position(|(b, m)| b != m);
+    Some(at.unwrap_or(base.len()))
+}
+
+/// Names with unpaired surrogates can't be spelled in UTF-8, so they are
+/// decoded lossily, with a warning, or rejected if `strict`. Their bytecode
+/// keeps the original name either way.
+fn from_utf16(bytes: &[u8], addr: u32, strict: bool) -> Result<(String, Option<Warning>), Error> {
+    if let Ok(name) = String::from_utf16le(bytes) {
+        return Ok((name, None));
+    }
+    let name = String::from_utf16le_lossy(bytes);
+    if strict {
+        return Err(Error::LoneSurrogate(name, addr));
+    }
+    Ok((name.clone(), Some(Warning::LoneSurrogate(name, addr))))
+}
+
+fn to_utf16(name: &str) -> Vec<u8> {
+    name.encode_utf16().flat_map(u16::to_le_bytes).collect()
 }
 
 fn cmp_utf16(lhs: &[u8], rhs: &[u8]) -> Ordering {
@@ -376,6 +1702,10 @@ trait OptionExt<T>: Sized {
     fn expect_mods(self) -> Result<T, Error> {
         self.expect(|| Error::NoMods)
     }
+
+    fn expect_function(self, name: &str) -> Result<T, Error> {
+        self.expect(|| Error::UnknownFunction(name.into()))
+    }
 }
 
 impl<T> OptionExt<T> for Option<T> {
@@ -395,3 +1725,341 @@ impl SliceExt for &[u8] {
         Ok(*chunk)
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Bytecode of a function named by the UTF-16 units `name`.
+    pub(crate) fn bytecode(name: &[u16], body: &[u8]) -> Vec<u8> {
+        let mut bytecode = vec![4];
+        bytecode.extend((name.len() as u32).to_le_bytes());
+        bytecode.extend(name.iter().flat_map(|unit| unit.to_le_bytes()));
+        bytecode.extend(body);
+        bytecode
+    }
+
+    pub(crate) fn function(name: &str, body: &[u8]) -> Vec<u8> {
+        bytecode(&name.encode_utf16().collect::<Vec<_>>(), body)
+    }
+
+    pub(crate) fn section(name: &[u8; 8], contents: &[u8]) -> Vec<u8> {
+        let mut section = name.to_vec();
+        section.extend((contents.len() as u64).to_le_bytes());
+        section.extend(contents);
+        section
+    }
+
+    /// The image of `functions`, laid out in order, and the table listing
+    /// them in order too.
+    pub(crate) fn image(functions: &[Vec<u8>]) -> [Vec<u8>; 2] {
+        let (mut prologues, mut named) = (vec![], vec![]);
+        let mut addr = 0u32;
+        for f in functions {
+            let name = extract_name(f, 0).unwrap();
+            if name == PROLOGUE {
+                prologues.push(addr);
+            } else {
+                named.push((addr, name));
+            }
+            addr += f.len() as u32;
+        }
+        [
+            section(b"image   ", &functions.concat()),
+            section(b"function", &table(&prologues, &named)),
+        ]
+    }
+
+    /// Contents of a function section listing prologues and named functions
+    /// at these addresses.
+    pub(crate) fn table(prologues: &[u32], named: &[(u32, &[u8])]) -> Vec<u8> {
+        let mut table = vec![];
+        table.extend((prologues.len() as u32).to_le_bytes());
+        table.extend(prologues.iter().flat_map(|addr| addr.to_le_bytes()));
+        table.extend(0u32.to_le_bytes());
+        table.extend((named.len() as u32).to_le_bytes());
+        for (addr, name) in named {
+            table.extend(addr.to_le_bytes());
+            table.extend(((name.len() / 2) as u32).to_le_bytes());
+            table.extend(*name);
+        }
+        table
+    }
+
+    /// Sections of a file made of `functions`, in the order `rebuild` writes.
+    pub(crate) fn sections(functions: &[Vec<u8>], global: &[u8], data: &[u8]) -> Vec<Vec<u8>> {
+        let [image, table] = image(functions);
+        vec![
+            image,
+            table,
+            section(b"global  ", global),
+            section(b"data    ", data),
+            section(b"conststr", &[0; 4]),
+            section(b"linkinf ", &[0; 16]),
+        ]
+    }
+
+    pub(crate) fn file(sections: &[Vec<u8>]) -> Vec<u8> {
+        let sections = sections.concat();
+        let mut file = MAGIC.to_vec();
+        file.extend((sections.len() as u64).to_le_bytes());
+        file.extend(sections);
+        file
+    }
+
+    pub(crate) fn base() -> Vec<u8> {
+        let functions = [
+            function("@Initialize", &EMPTY_PROLOGUE[27..]),
+            function("a", b"\x01"),
+            function("b", b"\x02\x02"),
+            function("c", b"\x03"),
+        ];
+        file(&sections(&functions, &[1; 4], &[2; 4]))
+    }
+
+    pub(crate) fn mods(functions: &[Vec<u8>]) -> Vec<u8> {
+        file(&sections(functions, &[1], &[2]))
+    }
+
+    #[test]
+    fn rebuild_round_trip() {
+        let base = base();
+        assert_eq!(CSX::new(&mut &base[..]).unwrap().rebuild().unwrap(), base);
+    }
+
+    #[test]
+    fn sections_in_any_order() {
+        let functions = [function("a", b"\x01"), function("b", b"\x02")];
+        let mut sections = sections(&functions, &[1], &[2]);
+        let ordered = CSX::new(&mut &file(&sections)[..]).unwrap();
+        sections.swap(0, 1);
+        sections.swap(2, 5);
+        let reordered = CSX::new(&mut &file(&sections)[..]).unwrap();
+        assert_eq!(reordered.rebuild().unwrap(), ordered.rebuild().unwrap());
+    }
+
+    #[test]
+    fn unapply_in_base_order() {
+        let original = base();
+        let base = CSX::new(&mut &original[..]).unwrap();
+        let prologue = function("@Initialize", b"\x05");
+        let mods = mods(&[prologue.clone(), function("b", b"\x04")]);
+        let mods = base.new_mods(&mut &mods[..]).unwrap();
+
+        // Patched by some other tool, which put the mods' prologue first.
+        let functions = [
+            prologue,
+            function("@Initialize", &EMPTY_PROLOGUE[27..]),
+            function("a", b"\x01"),
+            function("b", b"\x04"),
+            function("c", b"\x03"),
+        ];
+        let patched = file(&sections(&functions, &[1; 4], &[2; 4]));
+        let mut patched = CSX::new(&mut &patched[..]).unwrap();
+        patched.set_table_order(TableOrder::Base);
+        patched.unapply(&base, &mods).unwrap();
+        assert_eq!(patched.rebuild().unwrap(), original);
+    }
+
+    #[test]
+    fn rebuilt_header_length() {
+        let base = base();
+        let mut csx = CSX::new(&mut &base[..]).unwrap();
+        csx.set_tag("tag");
+        let rebuilt = csx.rebuild().unwrap();
+        let length = u64::from_le_bytes(rebuilt[56..64].try_into().unwrap());
+        assert_eq!(length as usize, rebuilt.len() - 64);
+    }
+
+    #[test]
+    fn header_length_mismatch() {
+        // Three short of the linkinf stub's end, which is zeros.
+        let short = base().len() as u64 - 64 - 3;
+        for declared in [5, short, u64::MAX] {
+            let mut base = base();
+            base[56..64].copy_from_slice(&declared.to_le_bytes());
+            let parsed = CSX::parse(&mut &base[..], <_>::default(), &<_>::default());
+            let (csx, warnings) = parsed.unwrap();
+            let [Warning::HeaderLength(d, actual)] = warnings[..] else {
+                panic!("expected a header length warning, got {warnings:?}");
+            };
+            assert_eq!((d, actual), (declared, base.len() as u64 - 64));
+            assert_eq!(csx.functions().len(), 4);
+        }
+    }
+
+    #[test]
+    fn trailing_padding() {
+        let base = base();
+        let padded = [&base[..], &[0; 16]].concat();
+        let parsed = CSX::parse(&mut &padded[..], <_>::default(), &<_>::default());
+        let (csx, warnings) = parsed.unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(csx.rebuild().unwrap(), base);
+    }
+
+    /// Parses a base with both functions `a` and `b` in the image, but the
+    /// function table listing `named`.
+    fn parse_table(named: &[(u32, &[u8])]) -> Result<CSX, Error> {
+        let functions = [function("a", b"\x01"), function("b", b"\x02")];
+        let mut sections = sections(&functions, &[1], &[2]);
+        sections[1] = section(b"function", &table(&[], named));
+        CSX::new(&mut &file(&sections)[..])
+    }
+
+    #[test]
+    fn duplicate_addresses() {
+        let result = parse_table(&[(0, b"a\0"), (0, b"a\0")]);
+        assert!(matches!(result, Err(Error::BadFunctionTable(0))));
+    }
+
+    #[test]
+    fn first_function_not_at_zero() {
+        let b = function("a", b"\x01").len() as u32;
+        let result = parse_table(&[(b, b"b\0")]);
+        assert!(matches!(result, Err(Error::BadFunctionTable(addr)) if addr == b));
+    }
+
+    #[test]
+    fn empty_function_section() {
+        let mut sections = sections(&[function("a", b"\x01")], &[1], &[2]);
+        sections[1] = section(b"function", &[]);
+        let result = CSX::new(&mut &file(&sections)[..]);
+        assert!(matches!(result, Err(Error::EmptyFunctionSection)));
+    }
+
+    #[test]
+    fn prologues_only() {
+        let prologues = mods(&[function("@Initialize", b"\x05")]);
+        let result = CSX::new(&mut &prologues[..]);
+        assert!(matches!(result, Err(Error::NoNamedFunctions)));
+
+        let base = CSX::new(&mut &base()[..]).unwrap();
+        assert!(base.new_mods(&mut &prologues[..]).is_ok());
+    }
+
+    /// Prologues of the base patched with two more, rebuilt by `policy`.
+    fn prologues_by(policy: ProloguePolicy) -> Vec<Vec<u8>> {
+        let mut csx = CSX::new(&mut &base()[..]).unwrap();
+        let prologues = [0x06, 0x07].map(|body| function("@Initialize", &[body]));
+        let mods = csx.new_mods(&mut &mods(&prologues)[..]).unwrap();
+        csx.apply_all_mods(mods).unwrap();
+        csx.set_prologue_policy(policy);
+        let rebuilt = CSX::new(&mut &csx.rebuild().unwrap()[..]).unwrap();
+        let prologues = rebuilt.functions.into_iter();
+        let prologues = prologues.filter(|f| f.name == "@Initialize");
+        prologues.map(|f| f.bytecode).collect()
+    }
+
+    #[test]
+    fn prologue_policies() {
+        let base = function("@Initialize", &EMPTY_PROLOGUE[27..]);
+        let [first, last] = [0x06, 0x07].map(|body| function("@Initialize", &[body]));
+        let all = [base.clone(), first, last.clone()];
+        assert_eq!(prologues_by(ProloguePolicy::KeepAll), all);
+        assert_eq!(prologues_by(ProloguePolicy::KeepLast), [last]);
+        let merged = [base.clone(), vec![0x06, 0x07]].concat();
+        assert_eq!(prologues_by(ProloguePolicy::Merge), [merged]);
+        assert_eq!(prologues_by(ProloguePolicy::KeepFirst), [base]);
+    }
+
+    #[test]
+    fn duplicate_function() {
+        let mut csx = CSX::new(&mut &base()[..]).unwrap();
+        let a = csx.functions[1].clone();
+        csx.functions.push(a);
+        let result = csx.rebuild();
+        assert!(matches!(result, Err(Error::DuplicateFunction(name)) if name == "a"));
+    }
+
+    #[test]
+    fn diff_removes_only_by_full_image() {
+        let base = CSX::new(&mut &base()[..]).unwrap();
+        let functions = [function("a", b"\x01"), function("d", b"\x04")];
+        let other = file(&sections(&functions, &[1; 4], &[2; 4]));
+
+        let image = CSX::new(&mut &other[..]).unwrap();
+        assert_eq!(base.diff(&image).removed, ["b", "c"]);
+        let mods = base.new_mods(&mut &other[..]).unwrap();
+        assert!(base.diff(&mods).removed.is_empty());
+        assert_eq!(base.diff(&mods).added, ["d"]);
+    }
+
+    #[test]
+    fn empty_prologues_collapse() {
+        let mut base = CSX::new(&mut &base()[..]).unwrap();
+        let empty = function("@Initialize", &EMPTY_PROLOGUE[27..]);
+        let mods = self::mods(&[empty.clone(), function("a", b"\x04"), empty]);
+        let parse_mods = || base.new_mods(&mut &mods[..]).unwrap();
+        let mut mods = parse_mods();
+        base.apply_all_mods(parse_mods()).unwrap();
+
+        mods.optimize_prologue();
+        let names: Vec<_> = mods.functions.iter().map(|f| &f.name).collect();
+        assert_eq!(names, ["a"]);
+
+        base.optimize_prologue();
+        let prologues = base.functions.iter().filter(|f| f.name == "@Initialize");
+        assert_eq!(prologues.count(), 1);
+    }
+
+    #[test]
+    fn set_empty_sections() {
+        let mut csx = CSX::new(&mut &base()[..]).unwrap();
+        let result = csx.set_global(vec![]);
+        assert!(matches!(result, Err(Error::BadSection(name)) if &name == b"global  "));
+        let result = csx.set_data(vec![]);
+        assert!(matches!(result, Err(Error::BadSection(name)) if &name == b"data    "));
+
+        csx.set_global(vec![1; 2]).unwrap();
+        csx.set_data(vec![2]).unwrap();
+        let rebuilt = CSX::new(&mut &csx.rebuild().unwrap()[..]).unwrap();
+        assert_eq!((rebuilt.global(), rebuilt.data()), (&[1; 2][..], &[2][..]));
+    }
+
+    #[test]
+    fn rename_function() {
+        let mut csx = CSX::new(&mut &base()[..]).unwrap();
+        csx.rename_function("b", "bb").unwrap();
+        let functions = [
+            function("@Initialize", &EMPTY_PROLOGUE[27..]),
+            function("a", b"\x01"),
+            function("bb", b"\x02\x02"),
+            function("c", b"\x03"),
+        ];
+        let rebuilt = csx.rebuild().unwrap();
+        assert_eq!(rebuilt, file(&sections(&functions, &[1; 4], &[2; 4])));
+
+        let reparsed = CSX::new(&mut &rebuilt[..]).unwrap();
+        let names: Vec<_> = reparsed.functions.iter().map(|f| &f.name).collect();
+        assert_eq!(names, ["@Initialize", "a", "bb", "c"]);
+        assert!(reparsed.base_func.contains_key("bb"));
+
+        let result = csx.rename_function("a", "c");
+        assert!(matches!(result, Err(Error::FunctionExists(name)) if name == "c"));
+        let result = csx.rename_function("a", "@Initialize");
+        assert!(matches!(result, Err(Error::BadFunctionName)));
+    }
+
+    #[test]
+    fn linkinf_round_trip() {
+        let functions = [function("a", b"\x01"), function("b", b"\x02")];
+        let mut sections = sections(&functions, &[1], &[2]);
+        let linkinf: Vec<u8> = (0..40).collect();
+        sections[5] = section(b"linkinf ", &linkinf);
+        let original = file(&sections);
+        let csx = CSX::new(&mut &original[..]).unwrap();
+        assert_eq!(csx.linkinf, linkinf);
+        assert_eq!(csx.rebuild().unwrap(), original);
+    }
+
+    #[test]
+    fn names_next_to_surrogates() {
+        let base = base();
+        let base = CSX::new(&mut &base[..]).unwrap();
+        let name = "\u{D7FF}\u{E000}\u{10000}";
+        let mods = mods(&[function(name, b"\x04")]);
+        let mods = base.new_mods(&mut &mods[..]).unwrap();
+        assert_eq!(mods.functions[0].name, name);
+    }
+}
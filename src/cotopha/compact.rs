@@ -1,172 +1,835 @@
+use std::cell::RefCell;
 use std::io::Read;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 
+use flate2::Compress;
+use flate2::Decompress;
+use flate2::FlushDecompress;
+use flate2::Status;
 use flate2::bufread::ZlibDecoder;
 use flate2::bufread::ZlibEncoder;
+use foldhash::HashMap;
+use foldhash::HashSet;
+use quick_error::ResultExt;
+use rayon::prelude::*;
 
+use super::BaseIndex;
 use super::CSX;
 use super::Error;
 use super::Function;
 use super::Hash;
+use super::HashAlgo;
 use super::OptionExt;
 use super::SliceExt;
 use super::String;
+use super::Warning;
 
-const MAGIC: &[u8; 8] = b"Senko\x1a\x00\x00";
-const HSIZE: usize = MAGIC.len() + size_of::<Hash>();
+const MAGIC: &[u8; 6] = b"Senko\x1a";
+/// Version 0 implies a SHA3-224 base hash, version 1 records the hash
+/// algorithm in the byte that follows the version. Version 2 is version 1
+/// with zlib entries compressed against a preset dictionary, derived from
+/// the base by `BaseResolver::dictionary`. Version 3 is version 1 with
+/// zstd entries too, flagged 0xC2, which is why no name in a version 3
+/// archive may hold that byte. Version 4 is version 3 with ` conststr `
+/// and ` linkinf ` entries too, which older versions read as functions,
+/// and a byte of flags after the hash: bit 0 for the preset dictionary.
+/// Archives with a name holding 0xC2 keep those entries at the version
+/// they would otherwise have, which this reader takes all the same.
+pub const VERSION: u8 = 4;
+/// Flag of a version 4 archive with a preset dictionary, as in version 2.
+const DICTIONARY_FLAG: u8 = 1;
+/// Size of the archive header, all `CompactCO::peek_hash_algo` reads.
+pub const HSIZE: usize = MAGIC.len() + 2 + size_of::<Hash>();
 const GLOBAL: &str = " global ";
 const DATA: &str = " data ";
+const CONSTSTR: &str = " conststr ";
+const LINKINF: &str = " linkinf ";
+/// Entries holding sections rather than functions.
+const SECTIONS: [&str; 4] = [GLOBAL, DATA, CONSTSTR, LINKINF];
+/// zlib looks back no further than its window, so that's all the preset
+/// dictionary needs.
+const DICTIONARY_SIZE: usize = 32 << 10;
+/// Size from which `CompactOptions::function_timeout` applies to an entry.
+/// bsdiff gets through smaller ones in no time, so moving them to a thread
+/// of their own would only cost copies of their inputs.
+const TIMEOUT_MIN_SIZE: usize = 64 << 10;
+/// zstd's highest level short of the memory hungry ultra ones.
+const ZSTD_LEVEL: i32 = 19;
+
+/// How entries are compressed, and how each entry ended up stored. Every
+/// codec falls back to storing an entry raw where compressing it would not
+/// make it any smaller.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    /// Every entry stored as is.
+    Raw,
+    /// bsdiff against the base where there is one, then zlib.
+    #[default]
+    Zlib,
+    /// Same as `Zlib`, then zstd on the same stream, keeping the smaller.
+    /// Makes a version 3 or later archive, so archives with a name holding
+    /// byte 0xC2 stick to zlib.
+    Zstd,
+}
+
+impl Codec {
+    pub const ALL: [Codec; 3] = [Codec::Raw, Codec::Zlib, Codec::Zstd];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Raw => "raw",
+            Codec::Zlib => "zlib",
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    /// Byte between an entry's name and its length.
+    fn marker(self) -> u8 {
+        match self {
+            Codec::Raw => 0xC0,
+            Codec::Zlib => 0xC1,
+            Codec::Zstd => 0xC2,
+        }
+    }
+
+    fn from_marker(marker: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|codec| codec.marker() == marker)
+    }
+}
+
+impl std::str::FromStr for Codec {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let codec = Self::ALL.into_iter().find(|codec| codec.name() == s);
+        codec.ok_or("expected `raw`, `zlib` or `zstd`")
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CompactOptions {
+    pub codec: Codec,
+    /// Leave `@Initialize` prologues out of the archive.
+    pub skip_prologue: bool,
+    /// Functions stored as is, without trying bsdiff and zlib on them. Saves
+    /// compression time on functions known to compress poorly, at the cost
+    /// of size if they actually would have.
+    pub raw: HashSet<String>,
+    /// Directory to dump every stream fed to zlib into, one file per entry:
+    /// `.bsdiff` for deltas against the base, `.bin` for standalone data.
+    pub keep_temp: Option<PathBuf>,
+    /// Directory of entries compressed by earlier runs, keyed by the hashes
+    /// of the base and mods bytecode they were made from, and stored with a
+    /// hash of their own to tell a damaged one.
+    pub cache_dir: Option<PathBuf>,
+    /// Time compressing a single entry may take before it is stored raw
+    /// instead, trading size for progress on inputs bsdiff is slow on. The
+    /// abandoned work can't be stopped and keeps a thread busy until done.
+    /// Only entries of at least `TIMEOUT_MIN_SIZE` bytes are timed.
+    pub function_timeout: Option<Duration>,
+    /// Prime zlib with the tail of the base image, which helps small
+    /// entries most, as they end before zlib would have learnt much on its
+    /// own. Makes a version 2 archive.
+    pub dictionary: bool,
+    /// zlib level from 0 to 9, the best if unset. Level 0 only stores, so
+    /// zlib never wins and every entry ends up raw: the quickest way to an
+    /// archive while iterating, with `keep_temp` to look at bsdiff streams.
+    pub level: Option<u32>,
+}
+
+/// Bounds on what a `.cco` can make the tool allocate, so that a crafted
+/// archive fails cleanly rather than exhausting memory.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_entries: usize,
+    /// Bytes restored from all entries together.
+    pub max_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_entries: 1 << 20,
+            max_size: 1 << 30,
+        }
+    }
+}
+
+/// How an entry ended up stored, as told by `CompactCO::explain`.
+#[derive(Debug)]
+pub struct EntryStats {
+    pub name: String,
+    pub codec: Codec,
+    /// Whether the stored stream is a bsdiff against the base.
+    pub diffed: bool,
+    pub size: usize,
+    pub original: usize,
+}
 
 pub struct CompactCO {
     base_hash: Hash,
+    hash_algo: HashAlgo,
+    limits: Limits,
+    dictionary: bool,
     entries: Vec<CompactEntry>,
+    fallbacks: Vec<String>,
+    warnings: Vec<Warning>,
 }
 
 pub struct CompactEntry {
     pub name: String,
-    pub zlib: bool,
+    pub codec: Codec,
     pub data: Vec<u8>,
 }
 
 impl CompactCO {
-    pub fn new(cco: &mut &[u8]) -> Result<Self, Error> {
-        let header = cco.split_off(..HSIZE).expect_eof()?;
-        let hash = header.strip_prefix(MAGIC).expect_magic()?;
-        let base_hash = Hash::try_from(hash).expect("bad size");
+    pub fn new(cco: &mut &[u8], limits: Limits) -> Result<Self, Error> {
+        let (version, hash_algo, base_hash) = read_header(cco)?;
+        let flags = match version {
+            4.. => *cco.split_off_first().expect_eof()?,
+            _ => 0,
+        };
+        if flags & !DICTIONARY_FLAG != 0 {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        let dictionary = version == 2 || flags & DICTIONARY_FLAG != 0;
+        let last = if version >= 3 {
+            Codec::Zstd
+        } else {
+            Codec::Zlib
+        };
 
         let mut entries = vec![];
+        let mut names = HashSet::default();
         while !cco.is_empty() {
+            if entries.len() == limits.max_entries {
+                return Err(Error::LimitExceeded("entries"));
+            }
             let size = cco
                 .iter()
-                .position(|&byte| (byte & !1) == 0xC0)
+                .position(|&byte| (0xC0..=last.marker()).contains(&byte))
                 .expect_eof()?;
             let name = cco.split_off(..size).expect_eof()?;
             let name = String::from_utf8(name)?;
-            let zlib = *cco.split_off_first().expect_eof()? == 0xC1;
+            // Sentinels share the namespace, so they too may appear only once.
+            // Prologues all go by `@Initialize`, one entry each.
+            if !name.starts_with("@") && !names.insert(name.clone()) {
+                return Err(Error::DuplicateEntry(name.trim().into()));
+            }
+            let codec = Codec::from_marker(*cco.split_off_first().expect_eof()?).unwrap();
             let len = u32::from_le_bytes(cco.split_off_chunk()?) as usize;
             let data = cco.split_off(..len).expect_eof()?.to_vec();
-            entries.push(CompactEntry { name, zlib, data });
+            entries.push(CompactEntry { name, codec, data });
         }
 
-        Ok(Self { base_hash, entries })
+        Ok(Self {
+            base_hash,
+            hash_algo,
+            limits,
+            dictionary,
+            entries,
+            fallbacks: vec![],
+            warnings: vec![],
+        })
+    }
+
+    /// The algorithm the archive at the start of `cco` identifies its base
+    /// by, from the header alone.
+    pub fn peek_hash_algo(mut cco: &[u8]) -> Result<HashAlgo, Error> {
+        Ok(read_header(&mut cco)?.1)
+    }
+
+    pub fn entries(&self) -> &[CompactEntry] {
+        &self.entries
+    }
+
+    /// Whether no entry restores a function, only global and data.
+    pub fn is_empty(&self) -> bool {
+        self.entries
+            .iter()
+            .all(|e| SECTIONS.contains(&e.name.as_str()))
     }
 
     pub fn rebuild(&self) -> Vec<u8> {
+        let is_new_section = |e: &CompactEntry| [CONSTSTR, LINKINF].contains(&e.name.as_str());
+        // From version 3 on, 0xC2 ends a name.
+        let holds_marker = |e: &CompactEntry| e.name.as_bytes().contains(&0xC2);
+        let marker_safe = !self.entries.iter().any(holds_marker);
+        let version = if marker_safe && self.entries.iter().any(is_new_section) {
+            VERSION
+        } else if self.entries.iter().any(|e| e.codec == Codec::Zstd) {
+            3
+        } else if self.dictionary {
+            2
+        } else if self.hash_algo == HashAlgo::default() {
+            0
+        } else {
+            1
+        };
+
         let mut cco = vec![];
         cco.extend_from_slice(MAGIC);
+        // Version 0 has a 0 for the default algorithm's id as well.
+        cco.extend_from_slice(&[version, self.hash_algo.id()]);
         cco.extend_from_slice(&self.base_hash);
+        if version == VERSION {
+            let flags = if self.dictionary { DICTIONARY_FLAG } else { 0 };
+            cco.push(flags);
+        }
 
         for e in &self.entries {
             cco.extend_from_slice(e.name.as_bytes());
-            cco.push(if e.zlib { 0xC1 } else { 0xC0 });
+            cco.push(e.codec.marker());
             cco.extend_from_slice(&(e.data.len() as u32).to_le_bytes());
             cco.extend_from_slice(&e.data);
         }
-        
+
         cco
     }
 
-    pub fn compress(base: &CSX, mods: &CSX) -> Result<Self, Error> {
+    /// Prologues have no counterpart in the base to diff against, so they
+    /// are stored standalone under their `@Initialize` name, in the order
+    /// they appear in the mods, and restored the same way by `decompress`.
+    pub fn compress(base: &CSX, mods: &CSX, options: &CompactOptions) -> Result<Self, Error> {
         super::validate_same_hash(base, mods)?;
         super::validate_items_same_prefix(base, mods)?;
 
-        let mut entries = vec![];
-        entries.push(CompactEntry::make(
-            String::new(GLOBAL),
-            Some(&base.global),
-            &mods.global,
-        )?);
-        entries.push(CompactEntry::make(
-            String::new(DATA),
-            Some(&base.data),
-            &mods.data,
-        )?);
+        for dir in options.keep_temp.iter().chain(&options.cache_dir) {
+            std::fs::create_dir_all(dir).context(dir.as_path())?;
+        }
+        // Parsing rejects such a base, but one may be put together otherwise,
+        // e.g. parsed as mods.
+        let mut warnings = vec![];
+        if base.base_func.is_empty() {
+            warnings.push(Warning::NoBaseFunctions);
+        }
+
+        let dictionary = options.dictionary && options.codec == Codec::Zlib;
+        let preset = dictionary.then(|| base.dictionary());
+        let preset = preset.as_deref();
+        let names = mods.functions.iter().map(|f| f.name.as_bytes());
+        let options = &CompactOptions {
+            codec: match options.codec {
+                Codec::Zstd if names.flatten().any(|&byte| byte == 0xC2) => Codec::Zlib,
+                codec => codec,
+            },
+            ..options.clone()
+        };
+
+        // A function bsdiff or zlib chokes on is stored raw, rather than
+        // failing the whole archive over it; the flag tells which fell back.
+        let make = |name: String, base_data, mods_data| {
+            if options.codec == Codec::Raw || options.raw.contains(&name) {
+                return Ok((CompactEntry::raw(name, mods_data), false));
+            }
+            let entry = match options.function_timeout {
+                Some(timeout) if mods_data.len() >= TIMEOUT_MIN_SIZE => CompactEntry::make_timed(
+                    name.clone(),
+                    base_data,
+                    mods_data,
+                    preset,
+                    options,
+                    timeout,
+                ),
+                _ => CompactEntry::make(name.clone(), base_data, mods_data, preset, options),
+            };
+            if let Err(Error::IO(_)) = entry {
+                return Ok((CompactEntry::raw(name, mods_data), true));
+            }
+            Ok((entry?, false))
+        };
+
+        let (global, data) = (String::new(GLOBAL), String::new(DATA));
+        let mut jobs = vec![
+            (global, base.resolve(GLOBAL), &mods.global[..]),
+            (data, base.resolve(DATA), &mods.data[..]),
+        ];
+        // Left out when there's no pool or links, so such archives read the
+        // same as they always have.
+        if !mods.conststr.is_empty() {
+            let conststr = String::new(CONSTSTR);
+            jobs.push((conststr, base.resolve(CONSTSTR), &mods.conststr));
+        }
+        if !mods.linkinf.is_empty() {
+            let linkinf = String::new(LINKINF);
+            jobs.push((linkinf, base.resolve(LINKINF), &mods.linkinf));
+        }
 
         for f in &mods.functions {
-            let index = base.base_func.get(&f.name);
-            let base_data = index.map(|&i| &base.functions[i].bytecode[..]);
-            let mods_data = &f.bytecode[..];
-            entries.push(CompactEntry::make(f.name.clone(), base_data, mods_data)?);
+            if options.skip_prologue && f.name.starts_with("@") {
+                continue;
+            }
+            validate_round_trip(f)?;
+            jobs.push((f.name.clone(), base.resolve(&f.name), &f.bytecode));
+        }
+
+        // Every entry is independent; collecting keeps them in job order, so
+        // the archive comes out the same however the work was split.
+        let made: Vec<_> = jobs
+            .into_par_iter()
+            .map(|(name, base_data, mods_data)| make(name, base_data, mods_data))
+            .collect::<Result<_, Error>>()?;
+        let mut entries = Vec::with_capacity(made.len());
+        let mut fallbacks = vec![];
+        for (entry, fell_back) in made {
+            if fell_back {
+                fallbacks.push(entry.name.clone());
+            }
+            entries.push(entry);
         }
 
         Ok(Self {
             base_hash: base.base_hash,
+            hash_algo: base.hash_algo,
+            limits: <_>::default(),
+            dictionary,
             entries,
+            fallbacks,
+            warnings,
         })
     }
 
+    pub fn base_hash(&self) -> &Hash {
+        &self.base_hash
+    }
+
+    pub fn hash_algo(&self) -> HashAlgo {
+        self.hash_algo
+    }
+
+    /// Restores every entry to tell how it was stored and what it saved.
+    pub fn explain(&self, base: &impl BaseResolver) -> Result<Vec<EntryStats>, Error> {
+        let preset = self.dictionary.then(|| base.dictionary());
+        self.entries
+            .iter()
+            .map(|e| {
+                let f = e.unpack(base, preset.as_deref(), self.limits.max_size)?;
+                let original = f.bytecode.len();
+                Ok(EntryStats {
+                    name: e.name.clone(),
+                    codec: e.codec,
+                    diffed: e.codec != Codec::Raw && base.resolve(&e.name).is_some(),
+                    size: e.data.len(),
+                    original,
+                })
+            })
+            .collect()
+    }
+
+    /// Lays entries out in the order `original` has them, so recompressing
+    /// an archive leaves its layout alone. Entries `original` lacks keep
+    /// their relative order after the rest.
+    pub fn keep_order_of(&mut self, original: &CompactCO) {
+        let entries = original.entries.iter().enumerate();
+        let position: HashMap<_, _> = entries.map(|(i, e)| (&e.name, i)).collect();
+        let position = |e: &CompactEntry| position.get(&e.name).copied();
+        self.entries
+            .sort_by_key(|e| position(e).unwrap_or(usize::MAX));
+    }
+
+    /// Entries `compress` had to store raw because compressing them failed,
+    /// or took longer than `function_timeout`.
+    pub fn fallbacks(&self) -> &[String] {
+        &self.fallbacks
+    }
+
+    /// Whatever `compress` found off about its inputs, for the caller to
+    /// report.
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
     pub fn decompress(&self, base: &CSX) -> Result<CSX, Error> {
+        self.decompress_(base, false)
+    }
+
+    /// Decompress against a base whose hash differs from the one recorded in
+    /// the archive, e.g. a re-serialized base with functions in another order.
+    /// Entries are mapped to base functions by name, and every restored
+    /// function has to carry its own name to be accepted. Nothing verifies
+    /// that those base functions are the ones the archive was made against,
+    /// which is for the caller to warn about.
+    pub fn decompress_loose(&self, base: &CSX) -> Result<CSX, Error> {
+        self.decompress_(base, true)
+    }
+
+    fn decompress_(&self, base: &CSX, loose: bool) -> Result<CSX, Error> {
         let mut mods = CSX {
-            base_hash: self.base_hash,
+            base_hash: if loose {
+                base.base_hash
+            } else {
+                self.base_hash
+            },
+            hash_algo: if loose {
+                base.hash_algo
+            } else {
+                self.hash_algo
+            },
+            digests: vec![],
             base_func: <_>::default(),
+            base_table: vec![],
+            table_order: <_>::default(),
+            prologue_policy: <_>::default(),
+            tag: vec![],
             mods_used: <_>::default(),
             global: vec![],
             data: vec![],
+            conststr: vec![],
+            linkinf: vec![],
             functions: vec![],
         };
 
         super::validate_same_hash(base, &mods)?;
         super::validate_items_same_prefix(base, &mods)?;
+        // Archives made with another algorithm join the mods list under the
+        // base's own identity, like the rest of it.
+        mods.base_hash = base.base_hash;
+        mods.hash_algo = base.hash_algo;
 
-        for e in &self.entries {
-            let f = e.unpack(base)?;
+        let preset = self.dictionary.then(|| base.dictionary());
+        let functions: Vec<_> = self
+            .entries
+            .par_iter()
+            .map(|e| e.unpack(base, preset.as_deref(), self.limits.max_size))
+            .collect::<Result<_, _>>()?;
+        let size: usize = functions.iter().map(|f| f.bytecode.len()).sum();
+        if size > self.limits.max_size {
+            return Err(Error::LimitExceeded("unpacked size"));
+        }
+
+        for f in functions {
             match f.name.as_str() {
                 GLOBAL => mods.global = f.bytecode,
                 DATA => mods.data = f.bytecode,
+                CONSTSTR => mods.conststr = f.bytecode,
+                LINKINF => mods.linkinf = f.bytecode,
                 _ => mods.functions.push(f),
             }
         }
 
+        // Same rule as for `.co` mods, which `CSX::new` enforces: an archive
+        // missing either entry must not empty the base's sections on apply.
+        if mods.global.is_empty() {
+            return Err(Error::BadSection(*b"global  "));
+        }
+        if mods.data.is_empty() {
+            return Err(Error::BadSection(*b"data    "));
+        }
+
         Ok(mods)
     }
+
+    pub fn unpack_one(&self, base: &BaseIndex, name: &str) -> Result<Function, Error> {
+        if base.base_hash_by(self.hash_algo) != Some(&self.base_hash) {
+            return Err(Error::HashMismatch);
+        }
+
+        let e = self.entries.iter().find(|e| e.name == name);
+        let preset = self.dictionary.then(|| base.dictionary());
+        let e = e.expect_function(name)?;
+        e.unpack(base, preset.as_deref(), self.limits.max_size)
+    }
 }
 
-impl CompactEntry {
-    pub fn make(name: String, base_data: Option<&[u8]>, mods_data: &[u8]) -> Result<Self, Error> {
-        let mut diff = vec![];
-        let stream = if let Some(base_data) = base_data {
-            bsdiff::diff(base_data, mods_data, &mut diff)?;
-            &diff
-        } else {
-            mods_data
-        };
-        let mut z = ZlibEncoder::new(stream, flate2::Compression::best());
-        let mut data = vec![];
-        z.read_to_end(&mut data)?;
+/// Reads the version of the archive, and the algorithm and hash it
+/// identifies its base by.
+fn read_header(cco: &mut &[u8]) -> Result<(u8, HashAlgo, Hash), Error> {
+    let header = cco.split_off(..HSIZE).expect_eof()?;
+    let header = header.strip_prefix(MAGIC).expect_magic()?;
+    let (&[version, id], hash) = header.split_first_chunk().expect_eof()?;
+    let hash_algo = match version {
+        0 if id == 0 => HashAlgo::Sha3_224,
+        0 => return Err(Error::BadMagic),
+        1..=VERSION => HashAlgo::from_id(id)?,
+        _ => return Err(Error::UnsupportedVersion(version)),
+    };
+    Ok((version, hash_algo, Hash::try_from(hash).expect("bad size")))
+}
+
+/// Where entries find the bytecode they were diffed against: the base
+/// function of the same name, or the base's own global and data sections
+/// for the ` global ` and ` data ` entries.
+pub trait BaseResolver {
+    fn resolve(&self, name: &str) -> Option<&[u8]>;
 
-        let zlib = data.len() < mods_data.len();
-        if !zlib {
-            data.clear();
-            data.extend_from_slice(mods_data);
+    /// The last `DICTIONARY_SIZE` bytes of the base image, or all of it if
+    /// shorter, for version 2 archives to preset zlib with.
+    fn dictionary(&self) -> Vec<u8>;
+}
+
+impl BaseResolver for CSX {
+    fn resolve(&self, name: &str) -> Option<&[u8]> {
+        match name {
+            GLOBAL => Some(&self.global),
+            DATA => Some(&self.data),
+            CONSTSTR => Some(&self.conststr),
+            LINKINF => Some(&self.linkinf),
+            name => {
+                let &index = self.base_func.get(name)?;
+                Some(&self.functions[index].bytecode)
+            }
         }
+    }
 
-        Ok(Self { name, zlib, data })
+    /// The functions of an unpatched base are its image, in order.
+    fn dictionary(&self) -> Vec<u8> {
+        let mut tail = vec![];
+        let mut left = DICTIONARY_SIZE;
+        for f in self.functions.iter().rev() {
+            let take = left.min(f.bytecode.len());
+            tail.push(&f.bytecode[f.bytecode.len() - take..]);
+            left -= take;
+            if left == 0 {
+                break;
+            }
+        }
+        tail.reverse();
+        tail.concat()
     }
+}
 
-    pub fn unpack(&self, base: &CSX) -> Result<Function, Error> {
-        if !self.zlib {
-            return Ok(Function {
-                name: self.name.clone(),
-                bytecode: self.data.clone(),
-            });
+impl BaseResolver for BaseIndex<'_> {
+    fn resolve(&self, name: &str) -> Option<&[u8]> {
+        match name {
+            GLOBAL => Some(self.global),
+            DATA => Some(self.data),
+            CONSTSTR => Some(self.conststr),
+            LINKINF => Some(self.linkinf),
+            name => self.function(name),
         }
+    }
 
-        let base_data = match self.name.as_str() {
-            GLOBAL => Some(&base.global[..]),
-            DATA => Some(&base.data[..]),
-            name => match base.base_func.get(name) {
-                index => index.map(|&i| &base.functions[i].bytecode[..]),
-            },
-        };
+    fn dictionary(&self) -> Vec<u8> {
+        let start = self.image.len().saturating_sub(DICTIONARY_SIZE);
+        self.image[start..].to_vec()
+    }
+}
+
+/// Names are stored as UTF-8 in `.cco` but as UTF-16 in the bytecode, so
+/// make sure restoring the name from UTF-8 gives back the very same bytes.
+fn validate_round_trip(f: &Function) -> Result<(), Error> {
+    let name = super::extract_name(&f.bytecode, 0)?;
+    if super::to_utf16(&f.name) != name {
+        return Err(Error::NameRoundTrip(f.name.clone()));
+    }
+    Ok(())
+}
+
+thread_local! {
+    /// Buffers for bsdiff and zlib streams, which only live until they are
+    /// copied out, compressed or patched: kept per thread so that the
+    /// allocations are reused across entries.
+    static SCRATCH: RefCell<(Vec<u8>, Vec<u8>)> = const { RefCell::new((vec![], vec![])) };
+}
+
+/// Runs `f` with both scratch buffers of this thread, emptied.
+fn with_scratch<T>(f: impl FnOnce(&mut Vec<u8>, &mut Vec<u8>) -> T) -> T {
+    SCRATCH.with_borrow_mut(|(a, b)| {
+        a.clear();
+        b.clear();
+        f(a, b)
+    })
+}
+
+fn hex(hash: &Hash) -> String {
+    hash.iter().map(|b| format!("{b:02x}")).collect()
+}
 
-        let mut z = ZlibDecoder::new(&self.data[..]);
-        let mut diff = vec![];
-        z.read_to_end(&mut diff)?;
+/// Entry name as a file name: path separators must not escape the directory,
+/// and the spaces around ` global ` and ` data ` only get in the way.
+fn temp_name(name: &str) -> String {
+    name.trim()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '\0' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+impl CompactEntry {
+    pub fn raw(name: String, mods_data: &[u8]) -> Self {
+        Self {
+            name,
+            codec: Codec::Raw,
+            data: mods_data.to_vec(),
+        }
+    }
+
+    /// Compresses with zlib preset to `dictionary`, if there is one, and
+    /// with zstd as well under `Codec::Zstd`.
+    pub fn make(
+        name: String,
+        base_data: Option<&[u8]>,
+        mods_data: &[u8],
+        dictionary: Option<&[u8]>,
+        options: &CompactOptions,
+    ) -> Result<Self, Error> {
+        let cache_path = options.cache_dir.as_ref().map(|dir| {
+            let base_hash = base_data.map_or("none".into(), |data| hex(&super::sha3_224(data)));
+            let mut key = format!("{base_hash}-{}", hex(&super::sha3_224(mods_data)));
+            if let Some(dictionary) = dictionary {
+                key = format!("{key}-{}", hex(&super::sha3_224(dictionary)));
+            }
+            if options.codec == Codec::Zstd {
+                key = format!("{key}-zstd");
+            }
+            if let Some(level) = options.level {
+                key = format!("{key}-{level}");
+            }
+            dir.join(key)
+        });
+        // Anything unreadable, or not matching the hash stored with it, is
+        // a miss, and gets overwritten.
+        if let Some(path) = &cache_path
+            && let Ok(cached) = std::fs::read(path)
+            && let Some((&marker, cached)) = cached.split_first()
+            && let Some(codec) = Codec::from_marker(0xC0 | marker)
+            && let Some((hash, data)) = cached.split_first_chunk::<{ size_of::<Hash>() }>()
+            && super::sha3_224(data) == *hash
+        {
+            let data = data.to_vec();
+            return Ok(Self { name, codec, data });
+        }
+
+        let (codec, data) = with_scratch(|diff, z_data| {
+            let stream = if let Some(base_data) = base_data {
+                bsdiff::diff(base_data, mods_data, diff)?;
+                &diff[..]
+            } else {
+                mods_data
+            };
+            if let Some(dir) = &options.keep_temp {
+                let ext = if base_data.is_some() { "bsdiff" } else { "bin" };
+                let path = dir.join(format!("{}.{ext}", temp_name(&name)));
+                super::write_file(&path, stream)?;
+            }
+            let level = options.level.map(flate2::Compression::new);
+            let mut deflate = Compress::new(level.unwrap_or(flate2::Compression::best()), true);
+            if let Some(dictionary) = dictionary {
+                let adler = deflate.set_dictionary(dictionary);
+                adler.map_err(std::io::Error::from)?;
+            }
+            let mut z = ZlibEncoder::new_with_compress(stream, deflate);
+            z.read_to_end(z_data)?;
+
+            let zstd = if options.codec == Codec::Zstd {
+                zstd::bulk::compress(stream, ZSTD_LEVEL)?
+            } else {
+                vec![]
+            };
 
-        let mut data = vec![];
-        if let Some(base_data) = base_data {
-            bsdiff::patch(base_data, &mut &diff[..], &mut data)?;
+            let (codec, data) = if !zstd.is_empty() && zstd.len() < z_data.len() {
+                (Codec::Zstd, &zstd[..])
+            } else {
+                (Codec::Zlib, &z_data[..])
+            };
+            if data.len() < mods_data.len() {
+                Ok::<_, Error>((codec, data.to_vec()))
+            } else {
+                Ok((Codec::Raw, mods_data.to_vec()))
+            }
+        })?;
+
+        if let Some(path) = &cache_path {
+            // The marker's low bits, so that 0 and 1 still mean raw and zlib.
+            let marker = codec.marker() & 3;
+            // Entries are made in parallel and two may share a key: renaming
+            // a finished file into place means no one reads a torn one.
+            static TEMP: AtomicUsize = AtomicUsize::new(0);
+            let n = TEMP.fetch_add(1, Ordering::Relaxed);
+            let temp = path.with_extension(format!("{}-{n}", std::process::id()));
+            let hash = super::sha3_224(&data);
+            super::write_file(&temp, &[&[marker], &hash[..], &data[..]].concat())?;
+            std::fs::rename(&temp, path).context(path.as_path())?;
+        }
+
+        Ok(Self { name, codec, data })
+    }
+
+    /// Like `make`, on a thread of its own, failing with a timed out IO error
+    /// if it takes longer than `timeout`.
+    fn make_timed(
+        name: String,
+        base_data: Option<&[u8]>,
+        mods_data: &[u8],
+        dictionary: Option<&[u8]>,
+        options: &CompactOptions,
+        timeout: Duration,
+    ) -> Result<Self, Error> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (base_data, mods_data) = (base_data.map(<[u8]>::to_vec), mods_data.to_vec());
+        let dictionary = dictionary.map(<[u8]>::to_vec);
+        let options = options.clone();
+        std::thread::spawn(move || {
+            let (base_data, dictionary) = (base_data.as_deref(), dictionary.as_deref());
+            let entry = Self::make(name, base_data, &mods_data, dictionary, &options);
+            // Nobody listens anymore if this took too long.
+            let _ = tx.send(entry);
+        });
+        match rx.recv_timeout(timeout) {
+            Ok(entry) => entry,
+            Err(_) => Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into()),
+        }
+    }
+
+    /// Fails rather than restore more than `max_size` bytes. `dictionary` is
+    /// the one `make` was given, if any.
+    pub fn unpack(
+        &self,
+        base: &impl BaseResolver,
+        dictionary: Option<&[u8]>,
+        max_size: usize,
+    ) -> Result<Function, Error> {
+        self.unpack_from(base.resolve(&self.name), dictionary, max_size)
+    }
+
+    fn unpack_from(
+        &self,
+        base_data: Option<&[u8]>,
+        dictionary: Option<&[u8]>,
+        max_size: usize,
+    ) -> Result<Function, Error> {
+        let data = if self.codec == Codec::Raw {
+            self.data.clone()
+        } else if self.data.is_empty() {
+            // Not even a zlib or zstd header, so no stream to inflate. `make`
+            // stores empty data raw, but both mean the same empty bytes.
+            vec![]
         } else {
-            data = diff;
+            with_scratch(|diff, _| {
+                if self.codec == Codec::Zstd {
+                    let z = zstd::Decoder::with_buffer(&self.data[..])?;
+                    z.take(max_size as u64 + 1).read_to_end(diff)?;
+                } else if let Some(dictionary) = dictionary {
+                    inflate_with(&self.data, dictionary, max_size + 1, diff)?;
+                } else {
+                    let z = ZlibDecoder::new(&self.data[..]);
+                    z.take(max_size as u64 + 1).read_to_end(diff)?;
+                }
+
+                let Some(base_data) = base_data else {
+                    return Ok::<_, Error>(diff.to_vec());
+                };
+                let mut data = vec![];
+                bsdiff::patch(base_data, &mut &diff[..], &mut data)?;
+                Ok(data)
+            })?
+        };
+        if data.len() > max_size {
+            return Err(Error::LimitExceeded("unpacked size"));
+        }
+
+        // A corrupt diff may restore garbage; catch it here rather than in
+        // `rebuild`, which expects every function to start with its name.
+        if !SECTIONS.contains(&self.name.as_str()) {
+            let name = super::extract_name(&data, 0).ok();
+            if name != Some(&super::to_utf16(&self.name)[..]) {
+                return Err(Error::CorruptEntry(self.name.clone()));
+            }
         }
 
         Ok(Function {
@@ -175,3 +838,400 @@ impl CompactEntry {
         })
     }
 }
+
+/// Inflates a zlib stream compressed against a preset `dictionary` into
+/// `out`, stopping once it holds `limit` bytes or more. The reader types
+/// can't do this, as zlib asks for the dictionary midway through the stream.
+fn inflate_with(
+    data: &[u8],
+    dictionary: &[u8],
+    limit: usize,
+    out: &mut Vec<u8>,
+) -> Result<(), Error> {
+    let mut inflate = Decompress::new(true);
+    loop {
+        out.reserve(DICTIONARY_SIZE);
+        let input = &data[inflate.total_in() as usize..];
+        match inflate.decompress_vec(input, out, FlushDecompress::Finish) {
+            Ok(Status::StreamEnd) => return Ok(()),
+            Ok(_) if out.len() >= limit => return Ok(()),
+            Ok(Status::BufError) if input.is_empty() => {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+            Ok(_) => (),
+            Err(err) if err.needs_dictionary().is_some() => {
+                let adler = inflate.set_dictionary(dictionary);
+                adler.map_err(std::io::Error::from)?;
+            }
+            Err(err) => return Err(std::io::Error::from(err).into()),
+        }
+    }
+}
+
+/// bsdiff of two whole images, zlib compressed: a coarser alternative to
+/// `.cco` archives, moving a base to a patched image in one go.
+pub fn whole_diff(old: &[u8], new: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut diff = vec![];
+    bsdiff::diff(old, new, &mut diff)?;
+    let mut z = ZlibEncoder::new(&diff[..], flate2::Compression::best());
+    let mut data = vec![];
+    z.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Restores the image a `whole_diff` from `old` was made to. Fails rather
+/// than restore more than `max_size` bytes, same as entries do.
+pub fn apply_whole_diff(old: &[u8], diff: &[u8], max_size: usize) -> Result<Vec<u8>, Error> {
+    let z = ZlibDecoder::new(diff);
+    let mut patch = vec![];
+    z.take(max_size as u64 + 1).read_to_end(&mut patch)?;
+
+    let mut new = vec![];
+    bsdiff::patch(old, &mut &patch[..], &mut new)?;
+    if new.len() > max_size {
+        return Err(Error::LimitExceeded("unpacked size"));
+    }
+    Ok(new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cotopha::ParseOptions;
+    use crate::cotopha::tests::*;
+
+    /// Compresses `mods` against `base`, made to fit it, and parses back
+    /// the archive.
+    fn compress(base: &CSX, mods: &[u8], options: &CompactOptions) -> CompactCO {
+        let mods = base.new_mods(&mut &mods[..]).unwrap();
+        let cco = CompactCO::compress(base, &mods, options).unwrap();
+        CompactCO::new(&mut &cco.rebuild()[..], Limits::default()).unwrap()
+    }
+
+    #[test]
+    fn archive_by_other_hash() {
+        let base = base();
+        let blake3 = CSX::new_with_hash(&mut &base[..], HashAlgo::Blake3).unwrap();
+        let cco = compress(&blake3, &mods(&[function("a", b"\x04")]), &<_>::default());
+
+        let sha3 = CSX::new(&mut &base[..]).unwrap();
+        assert!(matches!(cco.decompress(&sha3), Err(Error::HashMismatch)));
+
+        let options = ParseOptions {
+            also_digest: vec![HashAlgo::Blake3],
+            ..<_>::default()
+        };
+        let (mut sha3, _) = CSX::parse(&mut &base[..], HashAlgo::Sha3_224, &options).unwrap();
+        let mods = cco.decompress(&sha3).unwrap();
+        assert_eq!(mods.base_hash(), sha3.base_hash());
+        assert!(sha3.apply_all_mods(mods).is_ok());
+    }
+
+    #[test]
+    fn prologue_round_trip() {
+        let base = CSX::new(&mut &base()[..]).unwrap();
+        let prologue = function("@Initialize", b"\x05\x05");
+        let mods = mods(&[prologue.clone(), function("a", b"\x04")]);
+
+        let cco = compress(&base, &mods, &<_>::default());
+        let restored = cco.decompress(&base).unwrap();
+        let names: Vec<_> = restored.functions().iter().map(|f| &f.name).collect();
+        assert_eq!(names, ["@Initialize", "a"]);
+        assert_eq!(restored.functions()[0].bytecode, prologue);
+
+        let options = CompactOptions {
+            skip_prologue: true,
+            ..<_>::default()
+        };
+        let cco = compress(&base, &mods, &options);
+        let restored = cco.decompress(&base).unwrap();
+        let names: Vec<_> = restored.functions().iter().map(|f| &f.name).collect();
+        assert_eq!(names, ["a"]);
+    }
+
+    #[test]
+    fn two_prologues_round_trip() {
+        let base = CSX::new(&mut &base()[..]).unwrap();
+        let first = function("@Initialize", b"\x05\x05");
+        let second = function("@Initialize", b"\x06\x06");
+        let mods = mods(&[first.clone(), second.clone(), function("a", b"\x04")]);
+
+        let cco = compress(&base, &mods, &<_>::default());
+        let restored = cco.decompress(&base).unwrap();
+        let bytecode: Vec<_> = restored.functions().iter().map(|f| &f.bytecode).collect();
+        assert_eq!(bytecode[..2], [&first, &second]);
+    }
+
+    #[test]
+    fn damaged_cache_entry() {
+        let dir = std::env::temp_dir().join(format!("nyandere-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let options = CompactOptions {
+            cache_dir: Some(dir.clone()),
+            ..<_>::default()
+        };
+        let (base, mods) = (function("a", &[1; 64]), function("a", &[2; 64]));
+        let make = || CompactEntry::make("a".into(), Some(&base), &mods, None, &options).unwrap();
+        let made = make();
+
+        let [entry] = &std::fs::read_dir(&dir).unwrap().collect::<Vec<_>>()[..] else {
+            panic!("expected a single cached entry");
+        };
+        let path = entry.as_ref().unwrap().path();
+        let mut cached = std::fs::read(&path).unwrap();
+        *cached.last_mut().unwrap() ^= 1;
+        std::fs::write(&path, cached).unwrap();
+
+        assert_eq!(make().data, made.data);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// An archive for a SHA3-224 base of all zeros, holding `entries` of
+    /// names, codecs and data.
+    fn archive(entries: &[(&str, Codec, &[u8])]) -> Vec<u8> {
+        let mut cco = MAGIC.to_vec();
+        cco.extend([0, 0]);
+        cco.extend(Hash::default());
+        for (name, codec, data) in entries {
+            cco.extend(name.as_bytes());
+            cco.push(codec.marker());
+            cco.extend((data.len() as u32).to_le_bytes());
+            cco.extend(*data);
+        }
+        cco
+    }
+
+    #[test]
+    fn duplicate_entry() {
+        let entry = ("a", Codec::Raw, &b"\x01"[..]);
+        let cco = archive(&[entry, (GLOBAL, Codec::Raw, b"\x01"), entry]);
+        let result = CompactCO::new(&mut &cco[..], Limits::default());
+        assert!(matches!(result, Err(Error::DuplicateEntry(name)) if name == "a"));
+
+        let cco = archive(&[(DATA, Codec::Raw, b"\x01"), (DATA, Codec::Raw, b"\x02")]);
+        let result = CompactCO::new(&mut &cco[..], Limits::default());
+        assert!(matches!(result, Err(Error::DuplicateEntry(name)) if name == "data"));
+    }
+
+    /// A body zlib and zstd both make much smaller.
+    fn repetitive(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn zstd_round_trip() {
+        let base = CSX::new(&mut &base()[..]).unwrap();
+        let added = function("z", &repetitive(4096));
+        let mods = mods(&[function("a", b"\x04"), added.clone()]);
+        let options = CompactOptions {
+            codec: Codec::Zstd,
+            ..<_>::default()
+        };
+        let cco = compress(&base, &mods, &options);
+        let restored = cco.decompress(&base).unwrap();
+        let restored: Vec<_> = restored.functions().iter().map(|f| &f.bytecode).collect();
+        assert_eq!(restored, [&function("a", b"\x04"), &added]);
+
+        // Whichever codec won above, a zstd entry restores as well.
+        let entry = CompactEntry {
+            name: "z".into(),
+            codec: Codec::Zstd,
+            data: zstd::bulk::compress(&added, ZSTD_LEVEL).unwrap(),
+        };
+        let unpacked = entry.unpack(&base, None, 1 << 20).unwrap();
+        assert_eq!(unpacked.bytecode, added);
+    }
+
+    #[test]
+    fn legacy_markers() {
+        let base = CSX::new(&mut &base()[..]).unwrap();
+        let raw = function("a\u{B0}", b"\x04");
+        let zipped = function("d", &repetitive(256));
+        let mut zlib = vec![];
+        let mut z = ZlibEncoder::new(&zipped[..], flate2::Compression::best());
+        z.read_to_end(&mut zlib).unwrap();
+        let mut cco = archive(&[
+            (GLOBAL, Codec::Raw, b"\x01"),
+            (DATA, Codec::Raw, b"\x02"),
+            ("a\u{B0}", Codec::Raw, &raw),
+            ("d", Codec::Zlib, &zlib),
+        ]);
+        cco[MAGIC.len() + 2..HSIZE].copy_from_slice(base.base_hash());
+
+        let parsed = CompactCO::new(&mut &cco[..], Limits::default()).unwrap();
+        assert_eq!(parsed.rebuild(), cco);
+        let restored = parsed.decompress(&base).unwrap();
+        let restored: Vec<_> = restored.functions().iter().map(|f| &f.bytecode).collect();
+        assert_eq!(restored, [&raw, &zipped]);
+    }
+
+    #[test]
+    fn dictionary_round_trip() {
+        let mut x = 1u32;
+        let noise: Vec<u8> = (0..512)
+            .map(|_| {
+                x = x.wrapping_mul(1664525).wrapping_add(1013904223);
+                (x >> 24) as u8
+            })
+            .collect();
+        let options = CompactOptions::default();
+        let make = |f: &[u8], dictionary| {
+            CompactEntry::make("n".into(), None, f, dictionary, &options).unwrap()
+        };
+
+        // Noise only compresses against a dictionary holding it.
+        let f = function("n", &noise);
+        assert_eq!(make(&f, None).codec, Codec::Raw);
+        let entry = make(&f, Some(&noise));
+        assert_eq!(entry.codec, Codec::Zlib);
+        let unpacked = entry.unpack_from(None, Some(&noise), 1 << 20).unwrap();
+        assert_eq!(unpacked.bytecode, f);
+
+        // Nothing to gain from the dictionary, which inflating still needs.
+        let f = function("n", &repetitive(4096));
+        let entry = make(&f, Some(&noise));
+        assert_eq!(entry.codec, Codec::Zlib);
+        let unpacked = entry.unpack_from(None, Some(&noise), 1 << 20).unwrap();
+        assert_eq!(unpacked.bytecode, f);
+
+        let base = CSX::new(&mut &base()[..]).unwrap();
+        let functions = [function("a", b"\x04"), function("p", &repetitive(4096))];
+        let options = CompactOptions {
+            dictionary: true,
+            ..<_>::default()
+        };
+        let cco = compress(&base, &mods(&functions), &options);
+        assert_eq!(cco.rebuild()[MAGIC.len()], 2);
+        let restored = cco.decompress(&base).unwrap();
+        let restored: Vec<_> = restored.functions().iter().map(|f| &f.bytecode).collect();
+        assert_eq!(restored, [&functions[0], &functions[1]]);
+    }
+
+    #[test]
+    fn zstd_falls_back_on_marker_names() {
+        let base = CSX::new(&mut &base()[..]).unwrap();
+        let added = function("a\u{B0}", &repetitive(4096));
+        let mods = mods(std::slice::from_ref(&added));
+        let options = CompactOptions {
+            codec: Codec::Zstd,
+            ..<_>::default()
+        };
+        let cco = compress(&base, &mods, &options);
+        assert!(cco.entries().iter().all(|e| e.codec != Codec::Zstd));
+        assert!(cco.rebuild()[MAGIC.len()] < 3);
+        let restored = cco.decompress(&base).unwrap();
+        assert_eq!(restored.functions()[0].bytecode, added);
+    }
+
+    #[test]
+    fn zero_length_entries() {
+        let base = CSX::new(&mut &base()[..]).unwrap();
+        for codec in [Codec::Raw, Codec::Zlib] {
+            let entry = |name: &str| CompactEntry {
+                name: name.into(),
+                codec,
+                data: vec![],
+            };
+            let f = entry(CONSTSTR).unpack(&base, None, usize::MAX).unwrap();
+            assert!(f.bytecode.is_empty());
+            let result = entry("a").unpack(&base, None, usize::MAX);
+            assert!(matches!(result, Err(Error::CorruptEntry(name)) if name == "a"));
+        }
+
+        let cco = archive(&[(GLOBAL, Codec::Raw, b"\x01"), (LINKINF, Codec::Zlib, b"")]);
+        let cco = CompactCO::new(&mut &cco[..], Limits::default()).unwrap();
+        assert!(cco.entries()[1].data.is_empty());
+    }
+
+    #[test]
+    fn keep_order() {
+        let parse = |names: &[&str]| {
+            let entries = names.iter().map(|&name| (name, Codec::Raw, &b""[..]));
+            let cco = archive(&entries.collect::<Vec<_>>());
+            CompactCO::new(&mut &cco[..], Limits::default()).unwrap()
+        };
+        let original = parse(&["c", GLOBAL, "a", "b"]);
+        let mut cco = parse(&["a", "d", "b", GLOBAL, "e", "c"]);
+        cco.keep_order_of(&original);
+        let names: Vec<_> = cco.entries.iter().map(|e| &e.name).collect();
+        assert_eq!(names, ["c", GLOBAL, "a", "b", "d", "e"]);
+    }
+
+    #[test]
+    fn compress_against_no_base_functions() {
+        let data = base();
+        let base = CSX::new(&mut &data[..]).unwrap();
+        let base = base.new_mods(&mut &data[..]).unwrap();
+        let mods = mods(&[function("a", b"\x04")]);
+        let mods = base.new_mods(&mut &mods[..]).unwrap();
+        let cco = CompactCO::compress(&base, &mods, &<_>::default()).unwrap();
+        assert!(matches!(cco.warnings(), [Warning::NoBaseFunctions]));
+        let restored = cco.decompress(&base).unwrap();
+        let [restored] = restored.functions() else {
+            panic!("expected a single function");
+        };
+        assert_eq!(restored.bytecode, mods.functions()[0].bytecode);
+    }
+
+    #[test]
+    fn sections_bump_version() {
+        let base = CSX::new(&mut &base()[..]).unwrap();
+        let plain = mods(&[function("a", b"\x04")]);
+        let mut linked = sections(&[function("a", b"\x04")], &[1], &[2]);
+        linked[5] = section(b"linkinf ", &[3; 16]);
+        let linked = file(&linked);
+
+        for dictionary in [false, true] {
+            let options = CompactOptions {
+                dictionary,
+                ..<_>::default()
+            };
+            let version = |mods| compress(&base, mods, &options).rebuild()[6];
+            assert_eq!(version(&plain), if dictionary { 2 } else { 0 });
+            assert_eq!(version(&linked), VERSION);
+
+            let cco = compress(&base, &linked, &options);
+            assert_eq!(cco.dictionary, dictionary);
+            assert_eq!(cco.decompress(&base).unwrap().linkinf, [3; 16]);
+        }
+    }
+
+    #[test]
+    fn sections_round_trip() {
+        let base = CSX::new(&mut &base()[..]).unwrap();
+        let conststr: Vec<u8> = (0..24).collect();
+        let linkinf: Vec<u8> = (0..40).rev().collect();
+        for name in ["a", "a\u{B0}"] {
+            let mut mods = sections(&[function(name, b"\x04")], &[1], &[2]);
+            mods[4] = section(b"conststr", &conststr);
+            mods[5] = section(b"linkinf ", &linkinf);
+            let mods = file(&mods);
+
+            let cco = compress(&base, &mods, &<_>::default());
+            let restored = cco.decompress(&base).unwrap();
+            assert_eq!(restored.conststr, conststr);
+            assert_eq!(restored.linkinf, linkinf);
+            assert_eq!(restored.functions()[0].name, name);
+        }
+    }
+
+    #[test]
+    fn round_trip_next_to_surrogates() {
+        let name = "\u{D7FF}\u{E000}\u{10000}";
+        let f = Function {
+            name: name.into(),
+            bytecode: function(name, b"\x04"),
+        };
+        assert!(validate_round_trip(&f).is_ok());
+    }
+
+    #[test]
+    fn round_trip_lone_surrogate() {
+        let f = Function {
+            name: "a\u{FFFD}".into(),
+            bytecode: bytecode(&[0x61, 0xD800], b"\x04"),
+        };
+        let err = validate_round_trip(&f).unwrap_err();
+        assert!(matches!(err, Error::NameRoundTrip(name) if name == f.name));
+    }
+}
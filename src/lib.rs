@@ -0,0 +1,10 @@
+#[macro_use]
+extern crate quick_error;
+
+pub mod cotopha;
+
+pub use crate::cotopha::CSX;
+pub use crate::cotopha::Error;
+pub use crate::cotopha::Function;
+pub use crate::cotopha::compact::CompactCO;
+pub use crate::cotopha::compact::CompactEntry;
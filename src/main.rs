@@ -1,16 +1,41 @@
-#[macro_use]
-extern crate quick_error;
-
-mod cotopha;
-
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io::IsTerminal;
+use std::io::Read;
+use std::io::Write;
+use std::num::NonZeroUsize;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
 
 use color_print::cprintln;
+use compact_str::CompactString;
+use serde_json::json;
 
-use crate::cotopha::CSX;
-use crate::cotopha::Error;
-use crate::cotopha::compact::CompactCO;
+use nyandere::CSX;
+use nyandere::CompactCO;
+use nyandere::Error;
+use nyandere::Function;
+use nyandere::cotopha;
+use nyandere::cotopha::BaseIndex;
+use nyandere::cotopha::CsxDiff;
+use nyandere::cotopha::Hash;
+use nyandere::cotopha::HashAlgo;
+use nyandere::cotopha::HashWriter;
+use nyandere::cotopha::ParseOptions;
+use nyandere::cotopha::ProloguePolicy;
+use nyandere::cotopha::Resolution;
+use nyandere::cotopha::TableOrder;
+use nyandere::cotopha::Warning;
+use nyandere::cotopha::compact;
+use nyandere::cotopha::compact::Codec;
+use nyandere::cotopha::compact::CompactOptions;
+use nyandere::cotopha::compact::Limits;
+use nyandere::cotopha::sha3_224;
 
 #[derive(Default)]
 struct Args {
@@ -18,13 +43,203 @@ struct Args {
     mods: Vec<PathBuf>,
     output: Option<PathBuf>,
     compact: Vec<PathBuf>,
+    rename: Vec<(String, String)>,
+    extract: Option<(String, PathBuf)>,
+    stats_out: Option<PathBuf>,
+    verify_after_apply: bool,
+    ignore_hash: bool,
+    trace: bool,
+    hash: HashAlgo,
+    align: Option<NonZeroUsize>,
+    on_conflict: Resolution,
+    list_conflicts: bool,
+    digest: bool,
+    skip_prologue: bool,
+    profile: bool,
+    preserve_order: bool,
+    raw: Vec<String>,
+    unapply: Option<PathBuf>,
+    threads: Option<NonZeroUsize>,
+    normalize: Option<PathBuf>,
+    keep_temp: Option<PathBuf>,
+    expect_full: bool,
+    diff: Option<PathBuf>,
+    compact_result: Option<PathBuf>,
+    dump_header: bool,
+    verbose: bool,
+    output_format: Option<OutputFormat>,
+    max_function_size: Option<usize>,
+    interactive: bool,
+    /// Testing aid, left out of `--help`: a value to write into the output
+    /// header instead of the real section length, to produce malformed files
+    /// for checking how parsers cope with them.
+    force_header_size: Option<u64>,
+    dedupe_mods: bool,
+    if_exists: IfExists,
+    cache_dir: Option<PathBuf>,
+    decompress_dir: Option<PathBuf>,
+    out_dir: Option<PathBuf>,
+    dump_global: Option<PathBuf>,
+    dump_data: Option<PathBuf>,
+    set_global: Option<PathBuf>,
+    set_data: Option<PathBuf>,
+    report_unused_mods: bool,
+    plan_out: Option<PathBuf>,
+    max_entries: Option<usize>,
+    max_unpacked_size: Option<usize>,
+    rebase: Option<PathBuf>,
+    expect: Option<PathBuf>,
+    prologue: ProloguePolicy,
+    encoding_check: bool,
+    allow_empty: bool,
+    split_out: Option<PathBuf>,
+    global_from: Option<PathBuf>,
+    data_from: Option<PathBuf>,
+    list_sections: bool,
+    report_compat: bool,
+    keep_entry_order: bool,
+    explain_compact: bool,
+    derive_mod: Option<PathBuf>,
+    tag: bool,
+    compare_cco_sizes: bool,
+    whole_diff: Option<PathBuf>,
+    apply_whole_diff: Option<PathBuf>,
+    strict_utf16: bool,
+    function_timeout: Option<u64>,
+    order_file: Option<PathBuf>,
+    swap_sections: bool,
+    list_mod_targets: bool,
+    take_from: Option<PathBuf>,
+    function: Option<String>,
+    dictionary: bool,
+    list: bool,
+    codec: Codec,
+    level: Option<u32>,
+    strip_empty_prologue: bool,
+    verify: bool,
+}
+
+#[derive(Clone, Copy)]
+enum OutputFormat {
+    Csx,
+    Cco,
+}
+
+impl OutputFormat {
+    /// `.cco` for an `--output` ending in `.cco`, `.csx` for anything else.
+    fn infer(path: &Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("cco") => OutputFormat::Cco,
+            _ => OutputFormat::Csx,
+        }
+    }
+}
+
+/// What to do about `--output` and `--compact` paths that already exist.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+enum IfExists {
+    #[default]
+    Overwrite,
+    Error,
+    Skip,
+}
+
+impl std::str::FromStr for IfExists {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "overwrite" => Ok(IfExists::Overwrite),
+            "error" => Ok(IfExists::Error),
+            "skip" => Ok(IfExists::Skip),
+            _ => Err("expected `overwrite`, `error` or `skip`"),
+        }
+    }
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csx" => Ok(OutputFormat::Csx),
+            "cco" => Ok(OutputFormat::Cco),
+            _ => Err("expected `csx` or `cco`"),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Profile {
+    stages: Vec<(String, Duration)>,
+}
+
+impl Profile {
+    fn time<T>(&mut self, stage: impl FnOnce() -> String, f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = f();
+        self.stages.push((stage(), started.elapsed()));
+        result
+    }
+
+    fn report(&self) {
+        eprintln!("Profile:");
+        for (stage, elapsed) in &self.stages {
+            let ms = elapsed.as_secs_f64() * 1000.0;
+            eprintln!("  {stage:<40} {ms:>10.3} ms");
+        }
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    overridden: usize,
+    added: usize,
+    raw_entries: usize,
+    zlib_entries: usize,
+    zstd_entries: usize,
+    output_size: Option<usize>,
 }
 
+/// Paths are kept as raw `OsString`s all the way down, so file names in
+/// legacy encodings (e.g. Shift-JIS) still work; only names and numbers
+/// have to be valid unicode.
 fn parse_args() -> Result<Args, lexopt::Error> {
+    let mut args = Args::default();
+    // The project file goes first and the command line on top of it, so that
+    // its flags win regardless of where `--project` stands.
+    if let Some(project) = project_path()? {
+        let parser = lexopt::Parser::from_args(project_args(&project)?);
+        parse_args_into(&mut args, parser)?;
+    }
+    parse_args_into(&mut args, lexopt::Parser::from_env())?;
+    Ok(args)
+}
+
+/// The last `--project` of the command line, looked up ahead of parsing it,
+/// which skips over everything else.
+fn project_path() -> Result<Option<PathBuf>, lexopt::Error> {
     use lexopt::prelude::*;
 
     let mut parser = lexopt::Parser::from_env();
-    let mut args = Args::default();
+    let mut project = None;
+    while let Some(arg) = parser.next()? {
+        match arg {
+            Long("project") => project = Some(parser.value()?.into()),
+            // Whatever is attached, as in `--level=9` or `-ofile`.
+            Short(_) | Long(_) => drop(parser.optional_value()),
+            Value(_) => (),
+        }
+    }
+    Ok(project)
+}
+
+/// Lists given by one source of arguments replace the lists of an earlier
+/// source instead of extending them.
+fn parse_args_into(args: &mut Args, mut parser: lexopt::Parser) -> Result<(), lexopt::Error> {
+    use lexopt::prelude::*;
+
+    let mut cleared = std::collections::HashSet::new();
     while let Some(arg) = parser.next()? {
         match arg {
             Short('h') | Long("help") => {
@@ -33,17 +248,99 @@ fn parse_args() -> Result<Args, lexopt::Error> {
                 cprintln!("<s><g>Usage:</> <c>nyandere [OPTIONS]</></>\n");
 
                 cprintln!("<s><g>Options:</></>");
-                cprintln!("  <c><s>-b</></>, <c><s>--base</> <<BASE>></>     Base, single, unmodified <B><w><s>.csx</></></>, is required");
+                cprintln!("  <c><s>-b</></>, <c><s>--base</> <<BASE>></>     Base, single, unmodified <B><w><s>.csx</></></>, is required, stdin if <c>-</>");
                 cprintln!("  <c><s>-m</></>, <c><s>--mods</> <<MODS>></>     Mods list, <B><w><s>.co</></></> and <B><w><s>.cco</></></> are supported");
-                cprintln!("  <c><s>-o</></>, <c><s>--output</> <<PATH>></>   Apply mods list to the base and save at specified <c>PATH</>");
+                cprintln!("  <c><s>-o</></>, <c><s>--output</> <<PATH>></>   Apply mods list to the base and save at specified <c>PATH</>, compressed if it ends in <B><w><s>.cco</></></>, stdout if <c>-</>");
                 cprintln!("  <c><s>-c</></>, <c><s>--compact</> <<PATHS>></> Compress mods list and save them at updated <c>PATHS</> list");
+                cprintln!("      <c><s>--rename</> <<FROM>> <<TO>></> Rename function <c>FROM</> to <c>TO</> in the output");
+                cprintln!("      <c><s>--extract</> <<NAME>> <<PATH>></> Unpack function <c>NAME</> from <B><w><s>.cco</></></> mods and save its bytecode at <c>PATH</>");
+                cprintln!("      <c><s>--stats-out</> <<PATH>></> Write a JSON report of the run at <c>PATH</>");
+                cprintln!("      <c><s>--verify-after-apply</></> Re-read and parse the written output");
+                cprintln!("      <c><s>--ignore-hash</></> Restore <B><w><s>.cco</></></> mods made for another base by function names alone, unchecked");
+                cprintln!("      <c><s>--trace</></> Dump every parse step to stderr");
+                cprintln!("      <c><s>--hash</> <<ALGO>></> Base identity hash, <c>sha3-224</> (default) or <c>blake3</>");
+                cprintln!("      <c><s>--align</> <<BYTES>></> Pad the output with zeros to a multiple of <c>BYTES</>");
+                cprintln!("      <c><s>--on-conflict</> <<POLICY>></> Resolve mods conflicts with <c>error</> (default), <c>keep-old</> or <c>keep-new</>");
+                cprintln!("      <c><s>--list-conflicts</></> List functions defined by more than one mod, without applying");
+                cprintln!("      <c><s>--digest</></> Print SHA3-224 of every written file");
+                cprintln!("      <c><s>--skip-prologue</></> Leave <c>@Initialize</> prologues out of compressed mods");
+                cprintln!("      <c><s>--profile</></> Report time spent in each stage");
+                cprintln!("      <c><s>--preserve-order</></> Keep the base function table order instead of sorting it");
+                cprintln!("      <c><s>--raw</> <<NAMES>></> Store functions <c>NAMES</> uncompressed, trading size for speed");
+                cprintln!("      <c><s>--unapply</> <<PATCHED>></> Revert mods list from <c>PATCHED</> and save at <c>--output</>");
+                cprintln!("      <c><s>--threads</> <<N>></> Use at most <c>N</> threads, all cores by default");
+                cprintln!("      <c><s>--normalize</> <<PATH>></> Rebuild the base without any mods and save at <c>PATH</>");
+                cprintln!("      <c><s>--keep-temp</> <<DIR>></> Dump the streams compressed for every entry into <c>DIR</>, for debugging");
+                cprintln!("      <c><s>--project</> <<FILE>></> Read options from JSON <c>FILE</>, the command line takes precedence");
+                cprintln!("      <c><s>--expect-full</></> Require every mod to define all functions of the base");
+                cprintln!("      <c><s>--diff</> <<OTHER>></> List functions added, removed or modified by <c>OTHER</>, mods if <B><w><s>.co</></></> or <B><w><s>.cco</></></>, against the base");
+                cprintln!("      <c><s>--compact-result</> <<PATH>></> Compress the whole mods list into a single <B><w><s>.cco</></></> at <c>PATH</>");
+                cprintln!("      <c><s>--dump-header</></> Print the 64-byte header of the base as annotated hex");
+                cprintln!("      <c><s>--mods-dir</> <<DIR>></> Add mods from <c>DIR</> in the order listed by its <c>order.txt</>");
+                cprintln!("      <c><s>--output-format</> <<FORMAT>></> Save <c>--output</> as <c>csx</> or <c>cco</> regardless of its extension");
+                cprintln!("      <c><s>--max-function-size</> <<BYTES>></> Reject mods with a function larger than <c>BYTES</>");
+                cprintln!("      <c><s>--interactive</></> Ask which function to keep on every conflict, when run in a terminal");
+                cprintln!("      <c><s>--decompress-dir</> <<DIR>></> Decompress every <B><w><s>.cco</></></> in <c>DIR</> into <c>--out-dir</>");
+                cprintln!("      <c><s>--out-dir</> <<DIR>></> Where <c>--decompress-dir</> saves <B><w><s>.csx</></></> files");
+                cprintln!("      <c><s>--dedupe-mods</></> Apply mods files with identical contents only once");
+                cprintln!("      <c><s>--if-exists</> <<POLICY>></> On existing outputs, <c>overwrite</> (default), <c>error</> or <c>skip</>");
+                cprintln!("      <c><s>--cache-dir</> <<DIR>></> Reuse compressed entries from earlier runs, kept in <c>DIR</>");
+                cprintln!("      <c><s>--dump-global</> <<PATH>></> Save the <c>global</> section of the base at <c>PATH</>");
+                cprintln!("      <c><s>--dump-data</> <<PATH>></> Save the <c>data</> section of the base at <c>PATH</>");
+                cprintln!("      <c><s>--set-global</> <<PATH>></> Replace the <c>global</> section of the output with the one at <c>PATH</>");
+                cprintln!("      <c><s>--set-data</> <<PATH>></> Replace the <c>data</> section of the output with the one at <c>PATH</>");
+                cprintln!("      <c><s>--report-unused-mods</></> Warn about mods that change nothing in the base");
+                cprintln!("      <c><s>--plan-out</> <<PATH>></> Write what applying the mods list will do as JSON at <c>PATH</>");
+                cprintln!("      <c><s>--max-entries</> <<N>></> Reject <B><w><s>.cco</></></> mods with more than <c>N</> entries");
+                cprintln!("      <c><s>--max-unpacked-size</> <<BYTES>></> Reject <B><w><s>.cco</></></> mods that restore more than <c>BYTES</>");
+                cprintln!("      <c><s>--rebase</> <<NEW_BASE>></> Compress mods list against <c>NEW_BASE</> instead, saving at <c>--compact</> paths");
+                cprintln!("      <c><s>--expect</> <<GOLDEN>></> Fail unless the patched output matches <c>GOLDEN</>");
+                cprintln!("      <c><s>--prologue</> <<POLICY>></> Prologues to keep, <c>keep-all</> (default), <c>keep-first</>, <c>keep-last</> or <c>merge</>");
+                cprintln!("      <c><s>--encoding-check</></> List functions of the base and mods whose names are not ASCII");
+                cprintln!("      <c><s>--allow-empty</></> Write outputs without any functions instead of failing");
+                cprintln!("      <c><s>--split-out</> <<DIR>></> Compress every function of the mods list into its own <B><w><s>.cco</></></> in <c>DIR</>");
+                cprintln!("      <c><s>--global-from</> <<MODS>></> Take the <c>global</> section from <c>MODS</> rather than the longest one");
+                cprintln!("      <c><s>--data-from</> <<MODS>></> Take the <c>data</> section from <c>MODS</> rather than the longest one");
+                cprintln!("      <c><s>--list-sections</></> Print offset and length of every section of the base, reading headers only");
+                cprintln!("      <c><s>--report-compat</></> Check the base and <B><w><s>.co</></></> mods against every format rule, without stopping at the first");
+                cprintln!("      <c><s>--keep-entry-order</></> Keep the entry order of <B><w><s>.cco</></></> mods recompressed with <c>--compact</>");
+                cprintln!("      <c><s>--explain-compact</></> Print how every entry of compressed mods was stored, biggest savings first");
+                cprintln!("      <c><s>--derive-mod</> <<MODIFIED>></> Save mods turning the base into the full image <c>MODIFIED</> at <c>--output</>");
+                cprintln!("      <c><s>--tag</></> Record the version and command line in a <c>nyandere</> section of the output");
+                cprintln!("      <c><s>--compare-cco-sizes</></> Print the size every mods file compresses to with each codec, without saving");
+                cprintln!("      <c><s>--whole-diff</> <<PATH>></> Save a bsdiff of the whole rebuilt base to the patched output at <c>PATH</>");
+                cprintln!("      <c><s>--apply-whole-diff</> <<DIFF>></> Patch the rebuilt base with <c>--whole-diff</> output <c>DIFF</> and save at <c>--output</>");
+                cprintln!("      <c><s>--strict-utf16</></> Reject function names with unpaired surrogates instead of decoding them lossily");
+                cprintln!("      <c><s>--function-timeout</> <<SECS>></> Store functions of 64 KiB and up raw if they take longer than <c>SECS</> to compress");
+                cprintln!("      <c><s>--order-file</> <<FILE>></> List functions named in <c>FILE</>, one per line, first in the function table");
+                cprintln!("      <c><s>--swap-sections</></> Swap the <c>global</> and <c>data</> sections of the base, to repair a mixed up file");
+                cprintln!("      <c><s>--list-mod-targets</></> List the base functions each mod overrides and the ones it adds, without applying");
+                cprintln!("      <c><s>--take-from</> <<CSX>></> Copy <c>--function</> from <c>CSX</> into the base and save at <c>--output</>");
+                cprintln!("      <c><s>--function</> <<NAME>></> Name of the function <c>--take-from</> copies");
+                cprintln!("      <c><s>--dictionary</></> Compress entries against a zlib dictionary taken from the base, making a version 2 <B><w><s>.cco</></></>");
+                cprintln!("      <c><s>--codec</> <<CODEC>></> Compress entries with <c>raw</>, <c>zlib</> (default) or <c>zstd</>, which also tries zlib and makes a version 3 or later <B><w><s>.cco</></></>");
+                cprintln!("      <c><s>--level</> <<LEVEL>></> zlib level from <c>0</>, which stores every entry raw, to <c>9</> (default)");
+                cprintln!("      <c><s>--strip-empty-prologue</></> Drop prologues that do nothing from the output, keeping one if the base only has such");
+                cprintln!("      <c><s>--verify</></> Check the patched base parses back to the same functions, global and data, before any <c>--output</>");
+                cprintln!("  <c><s>-l</></>, <c><s>--list</></>            List the functions of the base, in image order");
+                cprintln!("  <c><s>-v</></>, <c><s>--verbose</></>         Also list base functions left untouched by <c>--diff</> mods");
+                cprintln!("  <c><s>-V</></>, <c><s>--version</></>         Print version and supported formats");
                 cprintln!("  <c><s>-h</></>, <c><s>--help</></>            Print help");
                 std::process::exit(0);
             }
+            Short('V') | Long("version") => {
+                println!("nyandere {}", env!("CARGO_PKG_VERSION"));
+                println!(".csx: Cotopha Image file");
+                println!(".cco: format versions 0 to {}", compact::VERSION);
+                std::process::exit(0);
+            }
             Short('b') | Long("base") => {
                 args.base = Some(parser.value()?.into());
             }
             Short('m') | Long("mods") => {
+                if cleared.insert("mods") {
+                    args.mods.clear();
+                }
                 for value in parser.values()? {
                     args.mods.push(value.into());
                 }
@@ -52,15 +349,321 @@ fn parse_args() -> Result<Args, lexopt::Error> {
                 args.output = Some(parser.value()?.into());
             }
             Short('c') | Long("compact") => {
+                if cleared.insert("compact") {
+                    args.compact.clear();
+                }
                 for value in parser.values()? {
                     args.compact.push(value.into());
                 }
             }
+            Long("rename") => {
+                if cleared.insert("rename") {
+                    args.rename.clear();
+                }
+                let from = parser.value()?.string()?;
+                let to = parser.value()?.string()?;
+                args.rename.push((from, to));
+            }
+            Long("extract") => {
+                let name = parser.value()?.string()?;
+                let path = parser.value()?.into();
+                args.extract = Some((name, path));
+            }
+            Long("stats-out") => {
+                args.stats_out = Some(parser.value()?.into());
+            }
+            Long("verify-after-apply") => {
+                args.verify_after_apply = true;
+            }
+            Long("ignore-hash") => {
+                args.ignore_hash = true;
+            }
+            Long("trace") => {
+                args.trace = true;
+            }
+            Long("hash") => {
+                args.hash = parser.value()?.parse()?;
+            }
+            Long("align") => {
+                args.align = Some(parser.value()?.parse()?);
+            }
+            Long("on-conflict") => {
+                args.on_conflict = parser.value()?.parse()?;
+            }
+            Long("list-conflicts") => {
+                args.list_conflicts = true;
+            }
+            Long("digest") => {
+                args.digest = true;
+            }
+            Long("skip-prologue") => {
+                args.skip_prologue = true;
+            }
+            Long("profile") => {
+                args.profile = true;
+            }
+            Long("preserve-order") => {
+                args.preserve_order = true;
+            }
+            Long("raw") => {
+                if cleared.insert("raw") {
+                    args.raw.clear();
+                }
+                for value in parser.values()? {
+                    args.raw.push(value.string()?);
+                }
+            }
+            Long("unapply") => {
+                args.unapply = Some(parser.value()?.into());
+            }
+            Long("threads") => {
+                args.threads = Some(parser.value()?.parse()?);
+            }
+            Long("normalize") => {
+                args.normalize = Some(parser.value()?.into());
+            }
+            Long("keep-temp") => {
+                args.keep_temp = Some(parser.value()?.into());
+            }
+            Long("project") => {
+                // Already read by `parse_args`, nested ones are ignored.
+                parser.value()?;
+            }
+            Long("expect-full") => {
+                args.expect_full = true;
+            }
+            Long("diff") => {
+                args.diff = Some(parser.value()?.into());
+            }
+            Long("compact-result") => {
+                args.compact_result = Some(parser.value()?.into());
+            }
+            Long("dump-header") => {
+                args.dump_header = true;
+            }
+            Long("mods-dir") => {
+                if cleared.insert("mods") {
+                    args.mods.clear();
+                }
+                args.mods.extend(mods_dir(&PathBuf::from(parser.value()?))?);
+            }
+            Long("codec") => {
+                args.codec = parser.value()?.parse()?;
+            }
+            Long("level") => {
+                let level = parser.value()?.parse()?;
+                if level > 9 {
+                    return Err(format!("invalid value for --level: {level} is not within 0 to 9").into());
+                }
+                args.level = Some(level);
+            }
+            Long("strip-empty-prologue") => {
+                args.strip_empty_prologue = true;
+            }
+            Long("verify") => {
+                args.verify = true;
+            }
+            Short('l') | Long("list") => {
+                args.list = true;
+            }
+            Short('v') | Long("verbose") => {
+                args.verbose = true;
+            }
+            Long("output-format") => {
+                args.output_format = Some(parser.value()?.parse()?);
+            }
+            Long("max-function-size") => {
+                args.max_function_size = Some(parser.value()?.parse()?);
+            }
+            Long("interactive") => {
+                args.interactive = true;
+            }
+            Long("force-header-size") => {
+                args.force_header_size = Some(parser.value()?.parse()?);
+            }
+            Long("decompress-dir") => {
+                args.decompress_dir = Some(parser.value()?.into());
+            }
+            Long("out-dir") => {
+                args.out_dir = Some(parser.value()?.into());
+            }
+            Long("dedupe-mods") => {
+                args.dedupe_mods = true;
+            }
+            Long("if-exists") => {
+                args.if_exists = parser.value()?.parse()?;
+            }
+            Long("cache-dir") => {
+                args.cache_dir = Some(parser.value()?.into());
+            }
+            Long("dump-global") => {
+                args.dump_global = Some(parser.value()?.into());
+            }
+            Long("dump-data") => {
+                args.dump_data = Some(parser.value()?.into());
+            }
+            Long("set-global") => {
+                args.set_global = Some(parser.value()?.into());
+            }
+            Long("set-data") => {
+                args.set_data = Some(parser.value()?.into());
+            }
+            Long("report-unused-mods") => {
+                args.report_unused_mods = true;
+            }
+            Long("plan-out") => {
+                args.plan_out = Some(parser.value()?.into());
+            }
+            Long("max-entries") => {
+                args.max_entries = Some(parser.value()?.parse()?);
+            }
+            Long("max-unpacked-size") => {
+                args.max_unpacked_size = Some(parser.value()?.parse()?);
+            }
+            Long("rebase") => {
+                args.rebase = Some(parser.value()?.into());
+            }
+            Long("expect") => {
+                args.expect = Some(parser.value()?.into());
+            }
+            Long("prologue") => {
+                args.prologue = parser.value()?.parse()?;
+            }
+            Long("encoding-check") => {
+                args.encoding_check = true;
+            }
+            Long("allow-empty") => {
+                args.allow_empty = true;
+            }
+            Long("split-out") => {
+                args.split_out = Some(parser.value()?.into());
+            }
+            Long("global-from") => {
+                args.global_from = Some(parser.value()?.into());
+            }
+            Long("data-from") => {
+                args.data_from = Some(parser.value()?.into());
+            }
+            Long("list-sections") => {
+                args.list_sections = true;
+            }
+            Long("report-compat") => {
+                args.report_compat = true;
+            }
+            Long("keep-entry-order") => {
+                args.keep_entry_order = true;
+            }
+            Long("explain-compact") => {
+                args.explain_compact = true;
+            }
+            Long("derive-mod") => {
+                args.derive_mod = Some(parser.value()?.into());
+            }
+            Long("tag") => {
+                args.tag = true;
+            }
+            Long("compare-cco-sizes") => {
+                args.compare_cco_sizes = true;
+            }
+            Long("whole-diff") => {
+                args.whole_diff = Some(parser.value()?.into());
+            }
+            Long("apply-whole-diff") => {
+                args.apply_whole_diff = Some(parser.value()?.into());
+            }
+            Long("strict-utf16") => {
+                args.strict_utf16 = true;
+            }
+            Long("function-timeout") => {
+                args.function_timeout = Some(parser.value()?.parse()?);
+            }
+            Long("order-file") => {
+                args.order_file = Some(parser.value()?.into());
+            }
+            Long("swap-sections") => {
+                args.swap_sections = true;
+            }
+            Long("list-mod-targets") => {
+                args.list_mod_targets = true;
+            }
+            Long("take-from") => {
+                args.take_from = Some(parser.value()?.into());
+            }
+            Long("function") => {
+                args.function = Some(parser.value()?.string()?);
+            }
+            Long("dictionary") => {
+                args.dictionary = true;
+            }
             _ => return Err(arg.unexpected()),
         }
     }
 
-    Ok(args)
+    Ok(())
+}
+
+/// Mods listed in `order.txt` of `dir`, one file name per line, in apply
+/// order. Blank lines and lines starting with `#` are skipped.
+fn mods_dir(dir: &Path) -> Result<Vec<PathBuf>, lexopt::Error> {
+    let manifest = dir.join("order.txt");
+    let order = std::fs::read_to_string(&manifest).map_err(|err| format!("{manifest:?}: {err}"))?;
+    let mods: Vec<_> = order
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| dir.join(line))
+        .collect();
+
+    // Listed as `./a.co` or `sub/../a.co`, it's still `a.co`.
+    let listed: std::collections::HashSet<_> =
+        mods.iter().filter_map(|path| path.canonicalize().ok()).collect();
+    let entries = std::fs::read_dir(dir).map_err(|err| format!("{dir:?}: {err}"))?;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.clone());
+        if path != manifest && !listed.contains(&canonical) {
+            eprintln!("Warning: {path:?} is not listed in {manifest:?}, ignoring.");
+        }
+    }
+    Ok(mods)
+}
+
+/// Turns a JSON project file into command line arguments, one option per
+/// key: `true` for a flag, a string or a number for its value, an array for
+/// several values, and an array of arrays to repeat the option.
+fn project_args(path: &Path) -> Result<Vec<OsString>, lexopt::Error> {
+    use serde_json::Value;
+
+    let data = std::fs::read(path).map_err(|err| format!("{path:?}: {err}"))?;
+    let project: Value = serde_json::from_slice(&data).map_err(|err| format!("{path:?}: {err}"))?;
+    let Some(project) = project.as_object() else {
+        return Err(format!("{path:?}: expected a JSON object").into());
+    };
+
+    let mut argv = vec![];
+    for (key, value) in project {
+        let groups: Vec<&[Value]> = match value {
+            Value::Bool(false) => vec![],
+            Value::Bool(true) => vec![&[]],
+            Value::Array(items) if items.iter().all(Value::is_array) => items
+                .iter()
+                .map(|item| &item.as_array().unwrap()[..])
+                .collect(),
+            Value::Array(items) => vec![items],
+            value => vec![std::slice::from_ref(value)],
+        };
+        for group in groups {
+            argv.push(format!("--{key}").into());
+            for value in group {
+                argv.push(match value {
+                    Value::String(value) => value.into(),
+                    Value::Number(value) => value.to_string().into(),
+                    _ => return Err(format!("{path:?}: bad value for `{key}`").into()),
+                });
+            }
+        }
+    }
+    Ok(argv)
 }
 
 fn report_lexopt_error(err: lexopt::Error) -> ! {
@@ -77,42 +680,185 @@ fn report_lexopt_error(err: lexopt::Error) -> ! {
             eprintln!("Unexpected value for option `{option}`.")
         }
         lexopt::Error::ParsingFailed { value, .. } => eprintln!("Failed to parse value `{value}`."),
-        lexopt::Error::NonUnicodeValue(_) => eprintln!("Non-unicode value."),
+        lexopt::Error::NonUnicodeValue(value) => eprintln!(
+            "Non-unicode value `{}` (only paths may be non-unicode).",
+            value.to_string_lossy()
+        ),
         lexopt::Error::Custom(error) => eprintln!("{error}."),
     }
     std::process::exit(1);
 }
 
+/// Reads stdin for a `-` path, so a streamed base can still be sniffed by magic.
 fn fs_read(path: &Path) -> Vec<u8> {
-    match std::fs::read(path) {
+    if is_stdio(path) {
+        return read_stdin().clone();
+    }
+    match cotopha::read_file(path) {
         Ok(bytes) => bytes,
-        Err(error) => {
-            eprintln!("IO error when trying to read a file at {path:?}.");
-            eprintln!("Reason: {error}.");
-            std::process::exit(1);
+        Err(err) => {
+            eprintln!("IO error when trying to read a file.");
+            report_error_reason(err);
         }
     }
 }
 
-fn fs_write(path: &Path, contents: Vec<u8>) {
-    if let Err(error) = std::fs::write(path, contents) {
-        eprintln!("IO error when trying to write a file at {path:?}.");
-        eprintln!("Reason: {error}.");
-        std::process::exit(1);
+fn rebuild(csx: &CSX) -> Vec<u8> {
+    match csx.rebuild() {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!("Failed to rebuild the image.");
+            report_error_reason(err);
+        }
     }
 }
 
-fn new_auto(path: PathBuf, base: Option<&CSX>) -> CSX {
-    let data = fs_read(&path);
-    let mut data_ptr = data.as_slice();
+/// Writes to stdout for a `-` path, leaving stderr for everything else.
+fn fs_write(path: &Path, contents: Vec<u8>) -> Hash {
+    let written = if is_stdio(path) {
+        write_stdout(&contents)
+    } else {
+        cotopha::write_file(path, &contents)
+    };
+    match written {
+        Ok(hash) => hash,
+        Err(err) => {
+            eprintln!("IO error when trying to write a file.");
+            report_error_reason(err);
+        }
+    }
+}
+
+/// Like `fs_write` of `rebuild`, without holding the whole image in memory.
+/// Also returns the size written, which is otherwise only known after.
+fn fs_write_csx(path: &Path, csx: &CSX) -> (Hash, usize) {
+    let written = if is_stdio(path) {
+        let mut w = HashWriter::new(std::io::stdout().lock());
+        csx.rebuild_to(&mut w).and_then(|()| {
+            w.flush()?;
+            let written = w.written();
+            Ok((w.finalize(), written))
+        })
+    } else {
+        cotopha::write_csx(path, csx)
+    };
+    match written {
+        Ok(written) => written,
+        Err(err) => {
+            eprintln!("Failed to write the rebuilt image.");
+            report_error_reason(err);
+        }
+    }
+}
+
+fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// Set when some output goes to stdout, which reports must then stay out of.
+static STDOUT_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Prints a report line to stdout, or to stderr if an output was written there.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if STDOUT_TAKEN.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Every single-file output of `args`, apart from the directories that
+/// `--split-out` and `--out-dir` fill.
+fn output_paths(args: &Args) -> impl Iterator<Item = &PathBuf> {
+    let paths = [
+        &args.output,
+        &args.compact_result,
+        &args.normalize,
+        &args.stats_out,
+        &args.plan_out,
+        &args.dump_global,
+        &args.dump_data,
+        &args.whole_diff,
+    ];
+    let extract = args.extract.as_ref().map(|(_, path)| path);
+    paths.into_iter().flatten().chain(&args.compact).chain(extract)
+}
+
+/// Whether any output path of `args` is stdout.
+fn takes_stdout(args: &Args) -> bool {
+    output_paths(args).any(|path| is_stdio(path))
+}
+
+/// Buffers all of stdin once, later reads (e.g. for `--stats-out`) get the same bytes.
+fn read_stdin() -> &'static Vec<u8> {
+    static STDIN: OnceLock<Vec<u8>> = OnceLock::new();
+    STDIN.get_or_init(|| {
+        let mut bytes = Vec::new();
+        if let Err(err) = std::io::stdin().lock().read_to_end(&mut bytes) {
+            eprintln!("IO error when trying to read stdin.");
+            report_error_reason(err.into());
+        }
+        if bytes.is_empty() {
+            eprintln!("Input error: stdin is empty.");
+            std::process::exit(1);
+        }
+        bytes
+    })
+}
+
+fn write_stdout(contents: &[u8]) -> Result<Hash, Error> {
+    let mut w = HashWriter::new(std::io::stdout().lock());
+    w.write_all(contents)?;
+    w.flush()?;
+    Ok(w.finalize())
+}
+
+/// Whether `path` may be written to, by `--if-exists`.
+fn may_write(path: &Path, if_exists: IfExists) -> bool {
+    if is_stdio(path) || !path.exists() {
+        return true;
+    }
+    match if_exists {
+        IfExists::Overwrite => true,
+        IfExists::Skip => {
+            eprintln!("Note: {path:?} already exists, skipping.");
+            false
+        }
+        IfExists::Error => {
+            eprintln!("Output error: {path:?} already exists.");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn hex(hash: &Hash) -> String {
+    hash.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn new_auto(
+    path: &Path,
+    data: &[u8],
+    base: Option<&CSX>,
+    loose: bool,
+    hash: HashAlgo,
+    limits: Limits,
+    options: &ParseOptions,
+) -> CSX {
+    let mut data_ptr = data;
     let csx = match base {
-        None => CSX::new(&mut data_ptr),
+        None if !data.starts_with(b"Entis\x1a\0\0") => {
+            eprintln!("Unrecognized file type for {path:?}, expected a .csx.");
+            std::process::exit(1);
+        }
+        None => CSX::parse(&mut data_ptr, hash, options),
         Some(base) => {
             if data.starts_with(b"Entis\x1a\0\0") {
-                base.new_mods(&mut data_ptr)
-            } else if data.starts_with(b"Senko\x1a\0\0") {
-                let cco = new_cco(&path, &data);
-                Ok(decompress_cco(&path, &cco, base))
+                base.parse_mods(&mut data_ptr, options)
+            } else if data.starts_with(b"Senko\x1a") {
+                let cco = new_cco(path, data, limits);
+                Ok((decompress_cco(path, &cco, base, loose), vec![]))
             } else {
                 eprintln!("Unrecognized file type for {path:?}.");
                 std::process::exit(1);
@@ -121,7 +867,12 @@ fn new_auto(path: PathBuf, base: Option<&CSX>) -> CSX {
     };
 
     match csx {
-        Ok(csx) => csx,
+        Ok((csx, warnings)) => {
+            for warning in warnings {
+                print_warning(Some(path), &warning);
+            }
+            csx
+        }
         Err(err) => {
             let rem = data_ptr.len();
             let at = data.len() - rem;
@@ -133,9 +884,9 @@ fn new_auto(path: PathBuf, base: Option<&CSX>) -> CSX {
     }
 }
 
-fn new_cco(path: &Path, data: &[u8]) -> CompactCO {
+fn new_cco(path: &Path, data: &[u8], limits: Limits) -> CompactCO {
     let mut data_ptr = data;
-    match CompactCO::new(&mut data_ptr) {
+    match CompactCO::new(&mut data_ptr, limits) {
         Ok(cco) => cco,
         Err(err) => {
             let rem = data_ptr.len();
@@ -148,20 +899,162 @@ fn new_cco(path: &Path, data: &[u8]) -> CompactCO {
     }
 }
 
-fn decompress_cco(path: &Path, cco: &CompactCO, base: &CSX) -> CSX {
-    match cco.decompress(base) {
-        Ok(csx) => csx,
+fn new_index<'a>(
+    path: &Path,
+    data: &'a [u8],
+    hash: HashAlgo,
+    options: &ParseOptions,
+) -> BaseIndex<'a> {
+    let mut data_ptr = data;
+    match BaseIndex::parse(&mut data_ptr, hash, options) {
+        Ok((index, warnings)) => {
+            for warning in warnings {
+                print_warning(Some(path), &warning);
+            }
+            index
+        }
+        Err(err) => {
+            let rem = data_ptr.len();
+            let at = data.len() - rem;
+            eprintln!("Parse error when trying to create BaseIndex.");
+            eprintln!("File: {path:?}");
+            eprintln!("Byte offset: {at}");
+            report_error_reason(err);
+        }
+    }
+}
+
+fn extract_function(base: &BaseIndex, mods: &[PathBuf], name: &str, limits: Limits) -> Vec<u8> {
+    for path in mods {
+        let data = fs_read(path);
+        if !data.starts_with(b"Senko\x1a") {
+            continue;
+        }
+
+        let cco = new_cco(path, &data, limits);
+        match cco.unpack_one(base, name) {
+            Ok(f) => return f.bytecode,
+            Err(Error::UnknownFunction(_)) => continue,
+            Err(err) => {
+                eprintln!("Decompression error when trying to unpack `{name}`.");
+                eprintln!("File: {path:?}");
+                report_error_reason(err);
+            }
+        }
+    }
+
+    eprintln!("Function `{name}` was not found in any of the .cco mods.");
+    std::process::exit(1);
+}
+
+/// Algorithms the `.cco` mods among `paths` identify the base by, so that
+/// the base gets digested by each. Anything unreadable is left for parsing
+/// to report.
+fn archive_hash_algos(paths: &[PathBuf]) -> Vec<HashAlgo> {
+    let mut algos = vec![];
+    for path in paths {
+        let mut header = [0; compact::HSIZE];
+        let Ok(mut file) = std::fs::File::open(path) else {
+            continue;
+        };
+        if file.read_exact(&mut header).is_err() {
+            continue;
+        }
+        if let Ok(algo) = CompactCO::peek_hash_algo(&header)
+            && !algos.contains(&algo)
+        {
+            algos.push(algo);
+        }
+    }
+    algos
+}
+
+fn decompress_cco(path: &Path, cco: &CompactCO, base: &CSX, loose: bool) -> CSX {
+    let csx = if loose {
+        cco.decompress_loose(base)
+    } else {
+        cco.decompress(base)
+    };
+    match csx {
+        Ok(csx) => {
+            if loose && base.base_hash_by(cco.hash_algo()) != Some(cco.base_hash()) {
+                eprintln!(
+                    "Warning: {path:?} was made for another base, its entries were restored \
+                     against the same-named functions of this one without verifying them."
+                );
+            }
+            csx
+        }
         Err(err) => {
             eprintln!("Decompression error during CompactCO to CSX restoration.");
             eprintln!("File: {path:?}");
+            if let Error::HashMismatch = err {
+                diagnose_hash_mismatch(cco, base);
+            }
             report_error_reason(err);
         }
     }
 }
 
-fn compress_cco(base: &CSX, mods: &CSX) -> CompactCO {
-    match CompactCO::compress(base, mods) {
-        Ok(cco) => cco,
+/// Explains a hash mismatch by restoring the archive against the base anyway
+/// and comparing what it references with what the base has.
+fn diagnose_hash_mismatch(cco: &CompactCO, base: &CSX) {
+    if let Some(hash) = base.base_hash_by(cco.hash_algo()) {
+        eprintln!("Base hash: {}", hex(hash));
+    }
+    eprintln!("Archive made for: {}", hex(cco.base_hash()));
+    let Ok(mods) = cco.decompress_loose(base) else {
+        eprintln!("The archive does not restore against this base at all.");
+        return;
+    };
+    let named = |csx: &CSX| {
+        csx.functions()
+            .iter()
+            .filter(|f| !f.name.starts_with("@"))
+            .count()
+    };
+    let (in_base, in_mods) = (named(base), named(&mods));
+    eprintln!("Base has {in_base} functions, the mods reference {in_mods}.");
+    let added = base.diff(&mods).added;
+    if !added.is_empty() {
+        let count = added.len();
+        eprintln!("{count} function names in the mods are not in the base:");
+        for name in added {
+            eprintln!("  `{name}`");
+        }
+    }
+    if let Some(at) = base.global_divergence(&mods) {
+        eprintln!("Global section differs from the base at byte {at}.");
+    }
+    if let Some(at) = base.data_divergence(&mods) {
+        eprintln!("Data section differs from the base at byte {at}, compared byte by byte.");
+    }
+}
+
+fn compact_options(args: &Args) -> CompactOptions {
+    CompactOptions {
+        codec: args.codec,
+        skip_prologue: args.skip_prologue,
+        raw: args.raw.iter().map(|name| name.as_str().into()).collect(),
+        keep_temp: None,
+        cache_dir: args.cache_dir.clone(),
+        function_timeout: args.function_timeout.map(Duration::from_secs),
+        dictionary: args.dictionary,
+        level: args.level,
+    }
+}
+
+fn compress_cco(base: &CSX, mods: &CSX, options: &CompactOptions) -> CompactCO {
+    match CompactCO::compress(base, mods, options) {
+        Ok(cco) => {
+            for warning in cco.warnings() {
+                print_warning(None, warning);
+            }
+            for name in cco.fallbacks() {
+                eprintln!("Warning: failed to compress `{name}`, stored it raw.");
+            }
+            cco
+        }
         Err(err) => {
             eprintln!("Compression error during CompactCO creation.");
             report_error_reason(err);
@@ -169,64 +1062,1119 @@ fn compress_cco(base: &CSX, mods: &CSX) -> CompactCO {
     }
 }
 
-fn concat_and_apply_mods(base: &mut CSX, all_mods: Vec<CSX>) -> Vec<u8> {
-    let mods = match CSX::concat_mods(all_mods) {
-        Ok(mods) => mods,
+fn explain_compact(path: &Path, cco: &CompactCO, base: &CSX) {
+    let mut entries = match cco.explain(base) {
+        Ok(entries) => entries,
         Err(err) => {
-            eprintln!("Failed to concatenate mods.");
+            eprintln!("Failed to restore entries to explain them.");
+            eprintln!("File: {path:?}");
             report_error_reason(err);
         }
     };
-    if let Err(err) = base.apply_all_mods(mods) {
-        eprintln!("Failed to apply mods.");
-        report_error_reason(err);
-    };
-    base.rebuild()
+    // Biggest savings first: the stored size alone ranks entries stored raw
+    // above those that compressed well.
+    entries.sort_by_key(|e| std::cmp::Reverse(e.original as i64 - e.size as i64));
+    status!("{path:?}:");
+    for e in entries {
+        let how = e.codec.name();
+        let diffed = if e.diffed { "bsdiff+" } else { "" };
+        let name = e.name.trim();
+        status!("  `{name}`: {diffed}{how}, {} -> {} bytes", e.original, e.size);
+    }
+}
+
+fn print_codec_sizes(path: &Path, sizes: &[(Codec, usize)]) {
+    status!("{path:?}:");
+    for &(codec, size) in sizes {
+        status!("  {}: {size} bytes", codec.name());
+    }
+    if let Some(&(best, _)) = sizes.iter().min_by_key(|&&(_, size)| size) {
+        status!("  best: {}", best.name());
+    }
+}
+
+/// Asks on the terminal which of two colliding functions to keep.
+fn prompt_resolution(name: &str, old: &Function, new: &Function) -> Resolution {
+    eprintln!(
+        "Conflict on `{name}`: existing is {} bytes, new is {} bytes.",
+        old.bytecode.len(),
+        new.bytecode.len()
+    );
+    loop {
+        eprint!("Keep [o]ld, use [n]ew or [a]bort? ");
+        let mut answer = String::new();
+        match std::io::stdin().read_line(&mut answer) {
+            Ok(0) | Err(_) => return Resolution::Error,
+            Ok(_) => (),
+        }
+        match answer.trim() {
+            "o" | "old" => return Resolution::KeepOld,
+            "n" | "new" => return Resolution::KeepNew,
+            "a" | "abort" => return Resolution::Error,
+            _ => continue,
+        }
+    }
+}
+
+fn concat_mods(all_mods: Vec<CSX>, on_conflict: Resolution, interactive: bool) -> CSX {
+    // The common case of a single mods has nothing to be merged with, and
+    // apply resolves any function it defines twice just as concat would.
+    if all_mods.len() == 1 {
+        return all_mods.into_iter().next().unwrap();
+    }
+    let mods = match on_conflict {
+        _ if interactive => CSX::concat_mods_with(all_mods, prompt_resolution),
+        Resolution::Error => CSX::concat_mods(all_mods),
+        policy => CSX::concat_mods_with(all_mods, |_, _, _| policy),
+    };
+    match mods {
+        Ok(mods) => mods,
+        Err(err) => {
+            eprintln!("Failed to concatenate mods.");
+            report_error_reason(err);
+        }
+    }
+}
+
+fn apply_mods(base: &mut CSX, mods: CSX, on_conflict: Resolution, interactive: bool) {
+    let global_at = base.global_divergence(&mods);
+    let data_at = base.data_divergence(&mods);
+    let applied = match on_conflict {
+        _ if interactive => base.apply_all_mods_with(mods, prompt_resolution),
+        Resolution::Error => base.apply_all_mods(mods),
+        policy => base.apply_all_mods_with(mods, |_, _, _| policy),
+    };
+    if let Err(err) = applied {
+        eprintln!("Failed to apply mods.");
+        match (&err, global_at, data_at) {
+            (Error::IncompatibleGlobal, Some(at), _) => {
+                eprintln!("Global section diverges from the base at byte {at}.")
+            }
+            (Error::IncompatibleData, _, Some(at)) => {
+                eprintln!("Data section diverges from the base at byte {at}, compared byte by byte.")
+            }
+            _ => (),
+        }
+        report_error_reason(err);
+    };
+}
+
+/// A section of the mods at `path` from the mods list, to pin it.
+fn pinned_section(
+    path: Option<&PathBuf>,
+    mod_paths: &[PathBuf],
+    all_mods: &[CSX],
+    section: fn(&CSX) -> &[u8],
+) -> Option<Vec<u8>> {
+    let path = path?;
+    match mod_paths.iter().position(|p| p == path) {
+        Some(index) => Some(section(&all_mods[index]).to_vec()),
+        None => {
+            eprintln!("Argument error: {path:?} is not in the mods list.");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Overrides the global and data sections `concat_mods` kept, which are the
+/// longest ones. The pinned ones must still be prefixes of them, and so of
+/// the base once applied.
+fn pin_sections(mods: &mut CSX, global: Option<Vec<u8>>, data: Option<Vec<u8>>) {
+    if let Some(global) = global
+        && let Err(err) = mods.set_global(global)
+    {
+        eprintln!("Failed to pin the global section given by --global-from.");
+        report_error_reason(err);
+    }
+    if let Some(data) = data
+        && let Err(err) = mods.set_data(data)
+    {
+        eprintln!("Failed to pin the data section given by --data-from.");
+        report_error_reason(err);
+    }
+}
+
+fn set_sections(base: &mut CSX, global: Option<&PathBuf>, data: Option<&PathBuf>) {
+    if let Some(path) = global
+        && let Err(err) = base.set_global(fs_read(path))
+    {
+        eprintln!("Failed to set the global section from {path:?}.");
+        report_error_reason(err);
+    }
+    if let Some(path) = data
+        && let Err(err) = base.set_data(fs_read(path))
+    {
+        eprintln!("Failed to set the data section from {path:?}.");
+        report_error_reason(err);
+    }
+}
+
+fn rebase_mods(mods: &mut CSX, new_base: &CSX, path: &Path) {
+    if let Err(err) = mods.rebase(new_base) {
+        eprintln!("Failed to rebase mods.");
+        eprintln!("File: {path:?}");
+        report_error_reason(err);
+    }
+}
+
+fn unapply_mods(patched: &mut CSX, base: &CSX, mods: &CSX) {
+    if let Err(err) = patched.unapply(base, mods) {
+        eprintln!("Failed to unapply mods.");
+        report_error_reason(err);
+    }
+}
+
+fn rename_function(base: &mut CSX, from: &str, to: &str) {
+    if let Err(err) = base.rename_function(from, to) {
+        eprintln!("Failed to rename `{from}` to `{to}`.");
+        report_error_reason(err);
+    }
+}
+
+fn order_functions(base: &mut CSX, path: &Path) {
+    let names = String::from_utf8_lossy(&fs_read(path)).into_owned();
+    let names = names.lines().map(str::trim);
+    let names: Vec<_> = names.filter(|line| !line.is_empty()).collect();
+    if let Err(err) = base.set_table_names(&names) {
+        eprintln!("Failed to order functions by {path:?}.");
+        report_error_reason(err);
+    }
+}
+
+/// Prints every rule `path` is held to and whether it holds, returning how
+/// many do not.
+fn report_compat(path: &Path, base: bool) -> usize {
+    println!("{path:?}:");
+    let mut broken = 0;
+    for check in cotopha::check_compat(&fs_read(path), base) {
+        match check.result {
+            Ok(()) => println!("  ok: {}", check.rule),
+            Err(err) => {
+                println!("  broken: {}", check.rule);
+                print_error_reason(err);
+                broken += 1;
+            }
+        }
+    }
+    broken
+}
+
+fn list_sections(path: &Path) {
+    let sections = if is_stdio(path) {
+        cotopha::scan_sections(&mut std::io::Cursor::new(read_stdin()))
+    } else {
+        match std::fs::File::open(path) {
+            Ok(mut file) => cotopha::scan_sections(&mut file),
+            Err(err) => Err(Error::FileIO(path.to_path_buf(), err)),
+        }
+    };
+    let sections = match sections {
+        Ok(sections) => sections,
+        Err(err) => {
+            eprintln!("Failed to scan sections.");
+            eprintln!("File: {path:?}");
+            report_error_reason(err);
+        }
+    };
+    for section in sections {
+        let (name, offset) = (section.name.escape_ascii(), section.offset);
+        println!("`{name}` at offset {offset}, {} bytes", section.length);
+    }
+}
+
+fn dump_header(data: &[u8]) {
+    let header = &data[..data.len().min(64)];
+    let length = match header.get(56..64) {
+        Some(length) => {
+            let length = u64::from_le_bytes(length.try_into().unwrap());
+            format!("section length, {length} bytes")
+        }
+        None => "section length".into(),
+    };
+    let regions = [
+        (0..8, "signature".into()),
+        (8..16, "reserved".into()),
+        (16..56, "format name".into()),
+        (56..64, length),
+    ];
+    for (range, label) in regions {
+        let region = header.get(range.start..).unwrap_or_default();
+        let region = &region[..region.len().min(range.len())];
+        for (i, line) in region.chunks(16).enumerate() {
+            let bytes: Vec<_> = line.iter().map(|b| format!("{b:02x}")).collect();
+            let line = format!("{:08x}  {}", range.start + 16 * i, bytes.join(" "));
+            if i == 0 {
+                println!("{line:<57}  # {label}");
+            } else {
+                println!("{line}");
+            }
+        }
+    }
+    if header.len() < 64 {
+        println!("# truncated, {} bytes out of 64", header.len());
+    }
+}
+
+/// Decompresses every `.cco` of `in_dir` to a `.csx` of the same stem in
+/// `out_dir`. A file that fails is reported and skipped; returns how many did.
+fn decompress_dir(
+    base: &CSX,
+    in_dir: &Path,
+    out_dir: &Path,
+    loose: bool,
+    limits: Limits,
+    if_exists: IfExists,
+) -> usize {
+    let mut paths: Vec<_> = match std::fs::read_dir(in_dir) {
+        Ok(entries) => entries.flatten().map(|entry| entry.path()).collect(),
+        Err(err) => {
+            eprintln!("IO error when trying to read a directory.");
+            report_error_reason(Error::FileIO(in_dir.to_path_buf(), err));
+        }
+    };
+    paths.retain(|path| path.extension().is_some_and(|ext| ext == "cco"));
+    paths.sort();
+
+    let mut failures = 0;
+    for path in paths {
+        let name = path.with_extension("csx");
+        let out_path = out_dir.join(name.file_name().unwrap());
+        if !may_write(&out_path, if_exists) {
+            continue;
+        }
+        let expanded = || -> Result<(), Error> {
+            let data = cotopha::read_file(&path)?;
+            let cco = CompactCO::new(&mut &data[..], limits)?;
+            let mods = if loose {
+                cco.decompress_loose(base)?
+            } else {
+                cco.decompress(base)?
+            };
+            cotopha::write_csx(&out_path, &mods)?;
+            Ok(())
+        };
+        if let Err(err) = expanded() {
+            eprintln!("Failed to decompress {path:?}.");
+            print_error_reason(err);
+            failures += 1;
+        }
+    }
+    failures
+}
+
+/// Saves every function of `mods` as a single-entry archive in `dir`, named
+/// after the function, so they can be applied one by one.
+fn split_out(base: &CSX, mods: &CSX, dir: &Path, options: &CompactOptions, if_exists: IfExists) {
+    if let Err(err) = std::fs::create_dir_all(dir) {
+        eprintln!("IO error when trying to create a directory.");
+        report_error_reason(Error::FileIO(dir.to_path_buf(), err));
+    }
+    let mut taken = std::collections::HashSet::new();
+    for part in mods.split() {
+        let name = &part.functions()[0].name;
+        let stem: String = name
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || "-_.".contains(c) {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        // Sanitizing may map different names to the same file.
+        let mut file_name = format!("{stem}.cco");
+        for n in 1.. {
+            if taken.insert(file_name.clone()) {
+                break;
+            }
+            file_name = format!("{stem}~{n}.cco");
+        }
+        let path = dir.join(file_name);
+        if may_write(&path, if_exists) {
+            fs_write(&path, compress_cco(base, &part, options).rebuild());
+        }
+    }
+}
+
+fn print_diff(diff: &CsxDiff, untouched: &[CompactString]) {
+    for (label, names) in [
+        ("Added", &diff.added[..]),
+        ("Removed", &diff.removed[..]),
+        ("Modified", &diff.modified[..]),
+        ("Untouched", untouched),
+    ] {
+        if names.is_empty() {
+            continue;
+        }
+        status!("{label}:");
+        for name in names {
+            status!("  `{name}`");
+        }
+    }
+    if diff.prologues > 0 {
+        status!("Prologues appended: {}", diff.prologues);
+    }
+    if diff.global_changed {
+        status!("Global section changed.");
+    }
+    if diff.data_changed {
+        status!("Data section changed.");
+    }
+}
+
+fn validate_function_size(path: &Path, mods: &CSX, max: usize) {
+    if let Err(err) = mods.validate_function_size(max) {
+        eprintln!("Mods rejected.");
+        eprintln!("File: {path:?}");
+        report_error_reason(err);
+    }
+}
+
+/// Mods that only restate base functions byte for byte, with the base's own
+/// global and data, were likely built against another base or went stale.
+fn report_unused_mods(base: &CSX, paths: &[PathBuf], all_mods: &[CSX]) {
+    for (path, mods) in std::iter::zip(paths, all_mods) {
+        let diff = base.diff(mods);
+        let changes = diff.added.len() + diff.modified.len() + base.classify_mod(mods).prologues;
+        if changes == 0 && !diff.global_changed && !diff.data_changed {
+            eprintln!("Warning: {path:?} has no effective changes.");
+        }
+    }
+}
+
+fn expect_full(base: &CSX, paths: &[PathBuf], all_mods: &[CSX]) {
+    let mut incomplete = false;
+    for (path, mods) in std::iter::zip(paths, all_mods) {
+        let missing = base.untouched_by(mods);
+        if missing.is_empty() {
+            continue;
+        }
+        incomplete = true;
+        eprintln!("{path:?} is missing {} base functions:", missing.len());
+        for name in missing {
+            eprintln!("  `{name}`");
+        }
+    }
+    if incomplete {
+        std::process::exit(1);
+    }
+}
+
+/// An output without functions almost always comes from a mistake, like
+/// mods that were all filtered out.
+fn reject_empty(path: &Path, empty: bool, allow_empty: bool) {
+    if empty && !allow_empty {
+        eprintln!("Refusing to write an output without functions, pass --allow-empty to force it.");
+        eprintln!("File: {path:?}");
+        std::process::exit(1);
+    }
+}
+
+fn has_no_functions(csx: &CSX) -> bool {
+    csx.functions().iter().all(|f| f.name.starts_with("@"))
+}
+
+/// Lists names with code points outside ASCII, which some tools choke on.
+fn encoding_check(path: &Path, csx: &CSX) {
+    for f in csx.functions() {
+        if f.name.is_ascii() {
+            continue;
+        }
+        let code_points: Vec<_> = f
+            .name
+            .chars()
+            .filter(|c| !c.is_ascii())
+            .map(|c| format!("U+{:04X}", c as u32))
+            .collect();
+        status!("{path:?}: `{}` {}", f.name, code_points.join(" "));
+    }
+}
+
+fn list_conflicts(paths: &[PathBuf], all_mods: &[CSX]) {
+    let mut defined_by = BTreeMap::<&str, Vec<&Path>>::new();
+    for (path, mods) in std::iter::zip(paths, all_mods) {
+        for f in mods.functions() {
+            if !f.name.starts_with("@") {
+                defined_by.entry(&f.name).or_default().push(path);
+            }
+        }
+    }
+
+    let mut conflicts = 0;
+    for (name, paths) in defined_by {
+        if paths.len() < 2 {
+            continue;
+        }
+        conflicts += 1;
+        status!("`{name}` is defined by:");
+        for path in paths {
+            status!("  {path:?}");
+        }
+    }
+
+    if conflicts == 0 {
+        status!("No conflicts found.");
+    } else {
+        status!("{conflicts} conflicting functions found.");
+    }
+}
+
+fn list_functions(base: &CSX) {
+    let mut prologues = 0;
+    for f in base.functions() {
+        if f.name.starts_with("@") {
+            prologues += 1;
+            println!("{} (prologue)", f.name);
+        } else {
+            println!("{}", f.name);
+        }
+    }
+    let named = base.functions().len() - prologues;
+    println!("{named} named functions, {prologues} prologues.");
+}
+
+/// The per-mod counterpart of `list_conflicts`: what each mod does to the
+/// base on its own.
+fn list_mod_targets(base: &CSX, paths: &[PathBuf], all_mods: &[CSX]) {
+    for (path, mods) in std::iter::zip(paths, all_mods) {
+        let targets = base.classify_mod(mods);
+        status!("{path:?}:");
+        for name in &targets.overridden {
+            status!("  overrides `{name}`");
+        }
+        for name in &targets.added {
+            status!("  adds `{name}`");
+        }
+        if targets.prologues > 0 {
+            status!("  prologues: {}", targets.prologues);
+        }
+    }
+}
+
+fn verify_output(path: &Path) {
+    let data = fs_read(path);
+    let mut data_ptr = data.as_slice();
+    if let Err(err) = CSX::new(&mut data_ptr) {
+        let rem = data_ptr.len();
+        let at = data.len() - rem;
+        eprintln!("Verification failed: written output does not parse as CSX.");
+        eprintln!("File: {path:?}");
+        eprintln!("Byte offset: {at}");
+        report_error_reason(err);
+    }
+}
+
+/// Parses `patched` back, which runs every check parsing does, and compares
+/// it against `base`, the mods applied, it was rebuilt from. Prologues are
+/// left out, since the prologue policy may have merged or dropped them.
+fn verify_patched(patched: &[u8], base: &CSX) {
+    let reparsed = match CSX::new(&mut &patched[..]) {
+        Ok(reparsed) => reparsed,
+        Err(err) => {
+            status!("FAIL");
+            eprintln!("The patched base does not parse back.");
+            report_error_reason(err);
+        }
+    };
+    let named = |csx: &CSX| {
+        let named = csx.functions().iter().filter(|f| !f.name.starts_with("@"));
+        named.map(|f| (f.name.clone(), f.bytecode.clone())).collect::<BTreeMap<_, _>>()
+    };
+    let (expected, found) = (named(base), named(&reparsed));
+    let mut failed = false;
+    for (name, bytecode) in &expected {
+        if found.get(name) != Some(bytecode) {
+            eprintln!("Function `{name}` did not survive the rebuild.");
+            failed = true;
+        }
+    }
+    for name in found.keys().filter(|name| !expected.contains_key(*name)) {
+        eprintln!("Function `{name}` appeared in the rebuild.");
+        failed = true;
+    }
+    if reparsed.global() != base.global() {
+        eprintln!("The global section did not survive the rebuild.");
+        failed = true;
+    }
+    if reparsed.data() != base.data() {
+        eprintln!("The data section did not survive the rebuild.");
+        failed = true;
+    }
+    if failed {
+        status!("FAIL");
+        std::process::exit(1);
+    }
+    status!("PASS");
+}
+
+/// Exits with a summary of what differs when `patched` is not `golden` byte
+/// for byte.
+fn expect_output(golden_path: &Path, patched: &[u8]) {
+    let data = fs_read(golden_path);
+    if data == patched {
+        return;
+    }
+    eprintln!("Patched output does not match {golden_path:?}.");
+    let mut data_ptr = data.as_slice();
+    let golden = match CSX::new(&mut data_ptr) {
+        Ok(golden) => golden,
+        Err(err) => {
+            let at = data.len() - data_ptr.len();
+            eprintln!("Parse error when trying to create CSX.");
+            eprintln!("File: {golden_path:?}");
+            eprintln!("Byte offset: {at}");
+            report_error_reason(err);
+        }
+    };
+    let mut patched_ptr = patched;
+    let patched = match CSX::new(&mut patched_ptr) {
+        Ok(patched) => patched,
+        Err(err) => {
+            let at = patched.len() - patched_ptr.len();
+            eprintln!("Parse error when trying to parse back the patched output.");
+            eprintln!("Byte offset: {at}");
+            report_error_reason(err);
+        }
+    };
+    let diff = golden.diff(&patched);
+    let changed = diff.global_changed || diff.data_changed;
+    if changed || !(diff.added.is_empty() && diff.removed.is_empty() && diff.modified.is_empty()) {
+        print_diff(&diff, &[]);
+    } else {
+        eprintln!("Functions and sections match, the layout differs.");
+    }
+    std::process::exit(1);
+}
+
+fn write_stats(path: &Path, stats: &Stats, inputs: &[&Path], started: Instant) {
+    let inputs: Vec<_> = inputs
+        .iter()
+        .map(|input| {
+            let hash = sha3_224(&fs_read(input));
+            json!({ "path": input.display().to_string(), "sha3_224": hex(&hash) })
+        })
+        .collect();
+
+    let report = json!({
+        "inputs": inputs,
+        "functions": {
+            "overridden": stats.overridden,
+            "added": stats.added,
+        },
+        "entries": {
+            "raw": stats.raw_entries,
+            "zlib": stats.zlib_entries,
+            "zstd": stats.zstd_entries,
+        },
+        "output_size": stats.output_size,
+        "elapsed_ms": started.elapsed().as_millis() as u64,
+    });
+
+    let mut contents = serde_json::to_vec_pretty(&report).expect("json serialization");
+    contents.push(b'\n');
+    fs_write(path, contents);
+}
+
+/// Mirrors what concat and apply do with the mods list, recording which mods
+/// file every function and the final global and data sections come from.
+fn write_plan(
+    path: &Path,
+    base: &CSX,
+    paths: &[PathBuf],
+    all_mods: &[CSX],
+    on_conflict: Resolution,
+    pinned: (Option<&PathBuf>, Option<&PathBuf>),
+) {
+    let in_base: std::collections::HashSet<_> =
+        base.functions().iter().map(|f| f.name.as_str()).collect();
+    let mut defined_by = std::collections::HashMap::new();
+    let (mut global_from, mut data_from) = (None, None);
+    let (mut global_len, mut data_len) = (0, 0);
+    let mut operations = vec![];
+    for (mods_path, mods) in std::iter::zip(paths, all_mods) {
+        let source = mods_path.display().to_string();
+        for f in mods.functions() {
+            let name = f.name.as_str();
+            let operation = if name.starts_with("@") {
+                json!({ "op": "prologue", "mod": source })
+            } else if let Some(first) = defined_by.get(name) {
+                let op = match on_conflict {
+                    Resolution::KeepOld => "keep-old",
+                    Resolution::KeepNew => "keep-new",
+                    Resolution::Error => "conflict",
+                };
+                json!({ "op": op, "function": name, "mod": source, "first": first })
+            } else {
+                defined_by.insert(name, source.clone());
+                let op = if in_base.contains(name) {
+                    "override"
+                } else {
+                    "add"
+                };
+                json!({ "op": op, "function": name, "mod": source })
+            };
+            operations.push(operation);
+        }
+        // Concat keeps the longest of the sections, the last one on ties.
+        if global_from.is_none() || mods.global().len() >= global_len {
+            (global_len, global_from) = (mods.global().len(), Some(source.clone()));
+        }
+        if data_from.is_none() || mods.data().len() >= data_len {
+            (data_len, data_from) = (mods.data().len(), Some(source));
+        }
+    }
+    // Unless `--global-from` and `--data-from` pin them.
+    let (pinned_global, pinned_data) = pinned;
+    if let Some(path) = pinned_global {
+        global_from = Some(path.display().to_string());
+    }
+    if let Some(path) = pinned_data {
+        data_from = Some(path.display().to_string());
+    }
+    if let Some(source) = global_from {
+        operations.push(json!({ "op": "set-global", "mod": source }));
+    }
+    if let Some(source) = data_from {
+        operations.push(json!({ "op": "set-data", "mod": source }));
+    }
+
+    let plan = json!({ "operations": operations });
+    let mut contents = serde_json::to_vec_pretty(&plan).expect("json serialization");
+    contents.push(b'\n');
+    fs_write(path, contents);
 }
 
 fn report_error_reason(err: Error) -> ! {
+    print_error_reason(err);
+    std::process::exit(1);
+}
+
+fn print_error_reason(err: Error) {
     eprint!("Reason: ");
     match err {
         Error::UnexpectedEof => eprintln!("Unexpected EOF."),
         Error::BadMagic => eprintln!("Bad magic."),
         Error::BadAddress => eprintln!("Bad address."),
         Error::BadFunctionName => eprintln!("Bad function name."),
+        Error::BadFunctionTable(addr) => {
+            eprintln!("Function table does not partition the image at address {addr:#x}.")
+        }
         Error::EpilogueNotEmpty => eprintln!("Epilogue is not empty."),
-        Error::DecodeUtf16 => eprintln!("Failed to decode utf-16."),
+        Error::LoneSurrogate(name, addr) => {
+            eprintln!("Function `{name}` at image offset {addr:#x} has unpaired surrogates in its name.")
+        }
         Error::DecodeUtf8(err) => eprintln!("Failed to decode utf-8 ({err})."),
         Error::UnknownSection(name) => eprintln!("Unknown section `{}`", name.escape_ascii()),
         Error::BadSection(name) => eprintln!("Bad section `{}`.", name.escape_ascii()),
+        Error::EmptyFunctionSection => eprintln!("The function section is empty."),
+        Error::NoNamedFunctions => eprintln!("The base has no named functions, only prologues."),
         Error::IncompatibleGlobal => eprintln!("Incompatible global section."),
         Error::IncompatibleData => eprintln!("Incompatible data section."),
+        Error::IncompatibleConststr => eprintln!("Incompatible conststr section."),
+        Error::IncompatibleLinkinf => eprintln!("Incompatible linkinf section."),
         Error::HashMismatch => eprintln!("Hash mismatch."),
         Error::NoMods => eprintln!("Cannot join mods if none are specified."),
         Error::ModsConflicts(name) => {
             eprintln!("Mods are in conflict with each other; failed to add `{name}` twice.")
         }
+        Error::UnknownFunction(name) => eprintln!("Unknown function `{name}`."),
+        Error::FunctionExists(name) => eprintln!("Function `{name}` already exists."),
+        Error::NameRoundTrip(name) => {
+            eprintln!("Function name `{name}` does not survive UTF-8 round trip.")
+        }
+        Error::UnsupportedVersion(version) => {
+            eprintln!("Unsupported format version {version}, the file is newer than this tool.")
+        }
+        Error::UnknownHash(id) => eprintln!("Unknown hash algorithm id {id}."),
+        Error::CorruptEntry(name) => {
+            eprintln!("Entry `{name}` does not restore to a function with that name.")
+        }
+        Error::DuplicateEntry(name) => eprintln!("Entry `{name}` appears more than once."),
+        Error::DuplicateFunction(name) => {
+            eprintln!("Function `{name}` would be listed twice in the function table.")
+        }
+        Error::FunctionTooLarge(name, size) => {
+            eprintln!("Function `{name}` is {size} bytes, over the size limit.")
+        }
+        Error::LimitExceeded(what) => eprintln!("Archive exceeds the limit on {what}."),
+        Error::FileIO(path, error) => eprintln!("{path:?}: {error}."),
         Error::IO(error) => eprintln!("{error}."),
     }
-    std::process::exit(1);
+}
+
+fn print_warning(path: Option<&Path>, warning: &Warning) {
+    match path {
+        Some(path) => eprint!("Warning: {path:?}: "),
+        None => eprint!("Warning: "),
+    }
+    match warning {
+        Warning::LoneSurrogate(name, addr) => {
+            eprintln!("function `{name}` at {addr:#x} has unpaired surrogates in its name.")
+        }
+        Warning::HeaderLength(declared, actual) => eprintln!(
+            "header declares {declared} bytes of sections but {actual} follow it, \
+             ignoring it."
+        ),
+        Warning::NoBaseFunctions => {
+            eprintln!("the base has no named functions, so every entry is stored standalone.")
+        }
+        Warning::SwappedSections(global, data) => eprintln!(
+            "`global` is {global} bytes and `data` is {data} bytes, \
+             the two sections may have been swapped."
+        ),
+    }
 }
 
 fn main() {
+    let started = Instant::now();
     let args = match parse_args() {
         Ok(args) => args,
         Err(e) => report_lexopt_error(e),
     };
+    STDOUT_TAKEN.store(takes_stdout(&args), Ordering::Relaxed);
 
-    let Some(base_path) = args.base else {
+    let mut archives = args.mods.clone();
+    if let Some(Ok(entries)) = args.decompress_dir.as_ref().map(std::fs::read_dir) {
+        archives.extend(entries.flatten().map(|entry| entry.path()));
+    }
+    let options = ParseOptions {
+        strict_utf16: args.strict_utf16,
+        trace: args.trace.then_some(|line| eprintln!("{line}")),
+        also_digest: archive_hash_algos(&archives),
+    };
+
+    if let Some(threads) = args.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads.get())
+            .build_global()
+            .expect("thread pool is only built once");
+    }
+
+    // Without a terminal to ask, conflicts go by `--on-conflict` as usual.
+    let interactive =
+        args.interactive && std::io::stdin().is_terminal() && std::io::stdout().is_terminal();
+
+    // Fail before doing any work rather than halfway through the outputs.
+    if args.if_exists == IfExists::Error {
+        for path in output_paths(&args) {
+            may_write(path, IfExists::Error);
+        }
+    }
+
+    let mut limits = Limits::default();
+    if let Some(max) = args.max_entries {
+        limits.max_entries = max;
+    }
+    if let Some(max) = args.max_unpacked_size {
+        limits.max_size = max;
+    }
+
+    let Some(base_path) = args.base.clone() else {
         eprintln!("Base .csx path is unspecified.");
         std::process::exit(1);
     };
 
-    let base = new_auto(base_path, None);
+    if args.dump_header {
+        dump_header(&fs_read(&base_path));
+        return;
+    }
+
+    if args.list_sections {
+        list_sections(&base_path);
+        return;
+    }
+
+    if args.report_compat {
+        let mut broken = report_compat(&base_path, true);
+        for path in &args.mods {
+            if path.extension().is_none_or(|ext| ext != "cco") {
+                broken += report_compat(path, false);
+            }
+        }
+        if broken > 0 {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some((name, path)) = &args.extract {
+        if !may_write(path, args.if_exists) {
+            return;
+        }
+        let data = fs_read(&base_path);
+        let base = new_index(&base_path, &data, args.hash, &options);
+        fs_write(path, extract_function(&base, &args.mods, name, limits));
+        return;
+    }
+
+    let mut profile = Profile::default();
+    let data = profile.time(|| format!("read {base_path:?}"), || fs_read(&base_path));
+    let mut base = profile.time(
+        || "parse base".into(),
+        || new_auto(&base_path, &data, None, false, args.hash, limits, &options),
+    );
+    drop(data);
+    if args.swap_sections {
+        base.swap_sections();
+    }
+
+    if args.list {
+        list_functions(&base);
+        return;
+    }
+
+    if let Some(path) = &args.dump_global
+        && may_write(path, args.if_exists)
+    {
+        fs_write(path, base.global().to_vec());
+    }
+    if let Some(path) = &args.dump_data
+        && may_write(path, args.if_exists)
+    {
+        fs_write(path, base.data().to_vec());
+    }
+
+    if let Some(in_dir) = &args.decompress_dir {
+        let Some(out_dir) = &args.out_dir else {
+            eprintln!("Argument error: --decompress-dir requires --out-dir.");
+            std::process::exit(1);
+        };
+        let (loose, if_exists) = (args.ignore_hash, args.if_exists);
+        if decompress_dir(&base, in_dir, out_dir, loose, limits, if_exists) > 0 {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Some(normalize_path) = &args.normalize {
+        if !may_write(normalize_path, args.if_exists) {
+            return;
+        }
+        let mut base = base;
+        if args.preserve_order {
+            base.set_table_order(TableOrder::Base);
+        }
+        let (hash, _) = fs_write_csx(normalize_path, &base);
+        if args.digest {
+            status!("{}  {}", hex(&hash), normalize_path.display());
+        }
+        return;
+    }
+
+    if let Some(diff_path) = &args.apply_whole_diff {
+        let Some(output_path) = &args.output else {
+            eprintln!("Argument error: --apply-whole-diff requires --output.");
+            std::process::exit(1);
+        };
+        if !may_write(output_path, args.if_exists) {
+            return;
+        }
+        let diff = fs_read(diff_path);
+        match compact::apply_whole_diff(&rebuild(&base), &diff, limits.max_size) {
+            Ok(patched) => fs_write(output_path, patched),
+            Err(err) => {
+                eprintln!("Failed to apply the whole diff.");
+                eprintln!("File: {diff_path:?}");
+                report_error_reason(err);
+            }
+        };
+        return;
+    }
+
+    if let Some(source_path) = &args.take_from {
+        let (Some(name), Some(output_path)) = (&args.function, &args.output) else {
+            eprintln!("Argument error: --take-from requires --function and --output.");
+            std::process::exit(1);
+        };
+        if !may_write(output_path, args.if_exists) {
+            return;
+        }
+        let data = fs_read(source_path);
+        let source = new_index(source_path, &data, args.hash, &options);
+        let Some(bytecode) = source.function(name) else {
+            eprintln!("Failed to take `{name}` from {source_path:?}.");
+            report_error_reason(Error::UnknownFunction(name.into()));
+        };
+        if let Err(err) = base.transplant(name, bytecode.to_vec()) {
+            eprintln!("Failed to take `{name}` from {source_path:?}.");
+            report_error_reason(err);
+        }
+        fs_write_csx(output_path, &base);
+        return;
+    }
 
+    if let Some(modified_path) = &args.derive_mod {
+        let Some(output_path) = &args.output else {
+            eprintln!("Argument error: --derive-mod requires --output.");
+            std::process::exit(1);
+        };
+        if !may_write(output_path, args.if_exists) {
+            return;
+        }
+        let data = fs_read(modified_path);
+        let hash = args.hash;
+        let modified = new_auto(modified_path, &data, None, false, hash, limits, &options);
+        for name in base.diff(&modified).removed {
+            eprintln!("Warning: {modified_path:?} lacks `{name}`, which mods cannot remove.");
+        }
+        let mods = base.derive_mods(&modified);
+        let format = args.output_format;
+        match format.unwrap_or(OutputFormat::infer(output_path)) {
+            OutputFormat::Csx => {
+                fs_write_csx(output_path, &mods);
+            }
+            OutputFormat::Cco => {
+                let cco = compress_cco(&base, &mods, &compact_options(&args));
+                fs_write(output_path, cco.rebuild());
+            }
+        }
+        return;
+    }
+
+    // The same file listed twice is a mistake rather than a conflict, so
+    // `--dedupe-mods` drops repeats by content before they get parsed.
+    let mut seen = std::collections::HashSet::new();
+    let mut mod_paths = vec![];
     let all_mods: Vec<_> = args
         .mods
-        .into_iter()
-        .map(|path| new_auto(path, Some(&base)))
+        .iter()
+        .filter_map(|path| {
+            let data = profile.time(|| format!("read {path:?}"), || fs_read(path));
+            if args.dedupe_mods && !seen.insert(sha3_224(&data)) {
+                eprintln!("Note: {path:?} is identical to an earlier mod, skipping.");
+                return None;
+            }
+            mod_paths.push(path.clone());
+            let (loose, hash) = (args.ignore_hash, args.hash);
+            Some(profile.time(
+                || format!("parse {path:?}"),
+                || new_auto(path, &data, Some(&base), loose, hash, limits, &options),
+            ))
+        })
         .collect();
 
+    if let Some(other_path) = &args.diff {
+        let data = fs_read(other_path);
+        let (loose, hash) = (args.ignore_hash, args.hash);
+        // Mods leave alone what they don't define, where a full image lacking
+        // a function removes it, so which one OTHER is decides the removals.
+        let is_mods = other_path.extension().is_some_and(|ext| ext == "co" || ext == "cco");
+        let base_of_other = is_mods.then_some(&base);
+        let other = new_auto(other_path, &data, base_of_other, loose, hash, limits, &options);
+        // Against a full image, what it leaves untouched is what it removes.
+        let untouched = (args.verbose && is_mods).then(|| base.untouched_by(&other));
+        let untouched = untouched.unwrap_or_default();
+        print_diff(&base.diff(&other), &untouched);
+        return;
+    }
+
+    if let Some(max) = args.max_function_size {
+        for (path, mods) in std::iter::zip(&mod_paths, &all_mods) {
+            validate_function_size(path, mods, max);
+        }
+    }
+
+    if args.report_unused_mods || args.verbose {
+        report_unused_mods(&base, &mod_paths, &all_mods);
+    }
+
+    if args.expect_full {
+        expect_full(&base, &mod_paths, &all_mods);
+    }
+
+    if args.encoding_check {
+        encoding_check(&base_path, &base);
+        for (path, mods) in std::iter::zip(&mod_paths, &all_mods) {
+            encoding_check(path, mods);
+        }
+        return;
+    }
+
+    if args.list_conflicts {
+        list_conflicts(&mod_paths, &all_mods);
+        return;
+    }
+
+    if args.list_mod_targets {
+        list_mod_targets(&base, &mod_paths, &all_mods);
+        return;
+    }
+
+    if let Some(patched_path) = &args.unapply {
+        let Some(output_path) = &args.output else {
+            eprintln!("Argument error: --unapply requires --output.");
+            std::process::exit(1);
+        };
+        if !may_write(output_path, args.if_exists) {
+            return;
+        }
+        let data = fs_read(patched_path);
+        let hash = args.hash;
+        let mut patched = new_auto(patched_path, &data, None, false, hash, limits, &options);
+        let mods = concat_mods(all_mods, args.on_conflict, interactive);
+        unapply_mods(&mut patched, &base, &mods);
+        reject_empty(output_path, has_no_functions(&patched), args.allow_empty);
+        fs_write_csx(output_path, &patched);
+        return;
+    }
+
+    if let Some(new_base_path) = &args.rebase {
+        if args.compact.len() != all_mods.len() {
+            eprintln!("Argument error: --rebase requires a --compact path for every mods file.");
+            std::process::exit(1);
+        }
+        let data = fs_read(new_base_path);
+        let hash = args.hash;
+        let new_base = new_auto(new_base_path, &data, None, false, hash, limits, &options);
+        let options = compact_options(&args);
+        for ((mut mods, path), modpath) in all_mods.into_iter().zip(&mod_paths).zip(&args.compact) {
+            if !may_write(modpath, args.if_exists) {
+                continue;
+            }
+            rebase_mods(&mut mods, &new_base, path);
+            let cco = compress_cco(&new_base, &mods, &options);
+            reject_empty(modpath, cco.is_empty(), args.allow_empty);
+            fs_write(modpath, cco.rebuild());
+        }
+        return;
+    }
+
+    if args.compare_cco_sizes {
+        for (path, mods) in std::iter::zip(&mod_paths, &all_mods) {
+            let mut sizes = vec![];
+            for codec in Codec::ALL {
+                let options = CompactOptions {
+                    codec,
+                    ..compact_options(&args)
+                };
+                sizes.push((codec, compress_cco(&base, mods, &options).rebuild().len()));
+            }
+            print_codec_sizes(path, &sizes);
+        }
+        return;
+    }
+
+    if let Some(dir) = &args.split_out {
+        let mods = concat_mods(all_mods, args.on_conflict, interactive);
+        split_out(&base, &mods, dir, &compact_options(&args), args.if_exists);
+        return;
+    }
+
+    if let Some(plan_path) = &args.plan_out
+        && may_write(plan_path, args.if_exists)
+    {
+        let pinned = (args.global_from.as_ref(), args.data_from.as_ref());
+        write_plan(plan_path, &base, &mod_paths, &all_mods, args.on_conflict, pinned);
+    }
+
+    let mut stats = Stats::default();
+    for mods in &all_mods {
+        let targets = base.classify_mod(mods);
+        stats.overridden += targets.overridden.len();
+        stats.added += targets.added.len();
+    }
+
     if !args.compact.is_empty() {
         if args.compact.len() > all_mods.len() {
             eprintln!(
@@ -237,9 +2185,41 @@ fn main() {
             std::process::exit(1);
         }
 
-        for (mods, modpath) in std::iter::zip(&all_mods, &args.compact) {
-            let cco = compress_cco(&base, mods).rebuild();
-            fs_write(modpath, cco);
+        let mut options = compact_options(&args);
+        for ((mods, path), modpath) in all_mods.iter().zip(&mod_paths).zip(&args.compact) {
+            if !may_write(modpath, args.if_exists) {
+                continue;
+            }
+            if let Some(dir) = &args.keep_temp {
+                // One subdirectory per archive, as mods may share names.
+                options.keep_temp = Some(dir.join(modpath.file_name().unwrap_or_default()));
+            }
+            let mut cco = profile.time(
+                || format!("compress {modpath:?}"),
+                || compress_cco(&base, mods, &options),
+            );
+            if args.keep_entry_order {
+                let data = fs_read(path);
+                if data.starts_with(b"Senko\x1a") {
+                    cco.keep_order_of(&new_cco(path, &data, limits));
+                }
+            }
+            reject_empty(modpath, cco.is_empty(), args.allow_empty);
+            if args.explain_compact {
+                explain_compact(modpath, &cco, &base);
+            }
+            for e in cco.entries() {
+                match e.codec {
+                    Codec::Raw => stats.raw_entries += 1,
+                    Codec::Zlib => stats.zlib_entries += 1,
+                    Codec::Zstd => stats.zstd_entries += 1,
+                }
+            }
+            let cco = cco.rebuild();
+            let hash = profile.time(|| format!("write {modpath:?}"), || fs_write(modpath, cco));
+            if args.digest {
+                status!("{}  {}", hex(&hash), modpath.display());
+            }
         }
 
         if args.compact.len() < all_mods.len() {
@@ -251,8 +2231,152 @@ fn main() {
         }
     }
 
-    if let Some(output_path) = &args.output {
-        let patched = concat_and_apply_mods(&mut { base }, all_mods);
-        fs_write(output_path, patched);
+    let output_format = match (&args.output, args.output_format) {
+        (Some(_), Some(format)) => Some(format),
+        (Some(path), None) => Some(OutputFormat::infer(path)),
+        (None, _) => None,
+    };
+    let (output_csx, output_cco) = match output_format {
+        Some(OutputFormat::Csx) => (args.output.as_ref(), None),
+        Some(OutputFormat::Cco) => (None, args.output.as_ref()),
+        None => (None, None),
+    };
+
+    let patches = args.output.is_some() || args.expect.is_some() || args.whole_diff.is_some();
+    let patches = patches || args.verify;
+    if patches || args.compact_result.is_some() {
+        let mut base = base;
+        let (global_from, data_from) = (args.global_from.as_ref(), args.data_from.as_ref());
+        let global = pinned_section(global_from, &mod_paths, &all_mods, CSX::global);
+        let data = pinned_section(data_from, &mod_paths, &all_mods, CSX::data);
+        let mut mods = profile.time(
+            || "concat".into(),
+            || concat_mods(all_mods, args.on_conflict, interactive),
+        );
+        pin_sections(&mut mods, global, data);
+        for cco_path in args.compact_result.iter().chain(output_cco) {
+            if !may_write(cco_path, args.if_exists) {
+                continue;
+            }
+            // The concatenated stack is exactly what apply adds to the base,
+            // without the unchanged functions and base prologues an archive of
+            // the patched image would carry. Archives in the mods list were
+            // restored against the base first, global and data included, so
+            // concat already kept the longest of those, or failed if they
+            // diverge, and the merged archive diffs the winner afresh.
+            let options = compact_options(&args);
+            let cco = profile.time(
+                || format!("compress {cco_path:?}"),
+                || compress_cco(&base, &mods, &options),
+            );
+            reject_empty(cco_path, cco.is_empty(), args.allow_empty);
+            if args.explain_compact {
+                explain_compact(cco_path, &cco, &base);
+            }
+            let hash = profile.time(
+                || format!("write {cco_path:?}"),
+                || fs_write(cco_path, cco.rebuild()),
+            );
+            if args.digest {
+                status!("{}  {}", hex(&hash), cco_path.display());
+            }
+        }
+        let output_csx = output_csx.filter(|path| may_write(path, args.if_exists));
+        let checks = args.expect.is_some() || args.whole_diff.is_some() || args.verify;
+        if output_csx.is_some() || checks {
+            let original = args.whole_diff.as_ref().map(|_| rebuild(&base));
+            profile.time(
+                || "apply".into(),
+                || apply_mods(&mut base, mods, args.on_conflict, interactive),
+            );
+            for (from, to) in &args.rename {
+                rename_function(&mut base, from, to);
+            }
+            set_sections(&mut base, args.set_global.as_ref(), args.set_data.as_ref());
+            if args.preserve_order {
+                base.set_table_order(TableOrder::Base);
+            }
+            if let Some(path) = &args.order_file {
+                order_functions(&mut base, path);
+            }
+            if args.strip_empty_prologue {
+                base.optimize_prologue();
+            }
+            base.set_prologue_policy(args.prologue);
+            if args.tag {
+                let args = std::env::args_os().skip(1);
+                let args: Vec<_> = args.map(|arg| arg.to_string_lossy().into_owned()).collect();
+                let version = env!("CARGO_PKG_VERSION");
+                base.set_tag(&format!("nyandere {version} {}", args.join(" ")));
+            }
+            if let Some(output_path) = output_csx {
+                reject_empty(output_path, has_no_functions(&base), args.allow_empty);
+            }
+            // Only the checks and the byte-level tweaks need the image in
+            // memory, a plain output is streamed straight from the functions.
+            let tweaks = args.align.is_some() || args.force_header_size.is_some();
+            let hash = if tweaks || checks {
+                let mut patched = profile.time(|| "rebuild".into(), || rebuild(&base));
+                if let Some(align) = args.align {
+                    patched.resize(patched.len().next_multiple_of(align.get()), 0);
+                }
+                if let Some(size) = args.force_header_size {
+                    patched[56..64].copy_from_slice(&size.to_le_bytes());
+                }
+                stats.output_size = Some(patched.len());
+                if let Some(golden_path) = &args.expect {
+                    expect_output(golden_path, &patched);
+                }
+                if args.verify {
+                    verify_patched(&patched, &base);
+                }
+                if let (Some(diff_path), Some(original)) = (&args.whole_diff, original)
+                    && may_write(diff_path, args.if_exists)
+                {
+                    match compact::whole_diff(&original, &patched) {
+                        Ok(diff) => fs_write(diff_path, diff),
+                        Err(err) => {
+                            eprintln!("Failed to diff the whole image.");
+                            report_error_reason(err);
+                        }
+                    };
+                }
+                output_csx.map(|output_path| {
+                    profile.time(
+                        || format!("write {output_path:?}"),
+                        || fs_write(output_path, patched),
+                    )
+                })
+            } else {
+                output_csx.map(|output_path| {
+                    let (hash, size) = profile.time(
+                        || format!("write {output_path:?}"),
+                        || fs_write_csx(output_path, &base),
+                    );
+                    stats.output_size = Some(size);
+                    hash
+                })
+            };
+            if let (Some(output_path), Some(hash)) = (output_csx, hash) {
+                if args.digest {
+                    status!("{}  {}", hex(&hash), output_path.display());
+                }
+                if args.verify_after_apply && !is_stdio(output_path) {
+                    verify_output(output_path);
+                }
+            }
+        }
+    }
+
+    if let Some(stats_path) = &args.stats_out
+        && may_write(stats_path, args.if_exists)
+    {
+        let mut inputs = vec![base_path.as_path()];
+        inputs.extend(args.mods.iter().map(PathBuf::as_path));
+        write_stats(stats_path, &stats, &inputs, started);
+    }
+
+    if args.profile {
+        profile.report();
     }
 }